@@ -0,0 +1,149 @@
+//! Throughput of the relay copy engine, compared with the previous one (a 16 KiB buffer per
+//! direction, read and write_all). Runs RELAYS concurrent copies over loopback TCP connections.
+//! TLS is left out, as it costs the same on both.
+//!
+//! Run with `cargo bench --bench relay_throughput`. Under `cargo test`, just a quick round is
+//! run, to check that it still works
+use std::time::{Duration, Instant};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+};
+
+use udstunnel::tunnel::{buffers, consts};
+
+const RELAYS: usize = 64;
+const BYTES_PER_RELAY: usize = 32 * 1024 * 1024;
+const ROUNDS: usize = 3;
+
+#[derive(Debug, Clone, Copy)]
+enum Engine {
+    Legacy,
+    Pooled,
+}
+
+async fn legacy_copy(mut reader: OwnedReadHalf, mut writer: OwnedWriteHalf) -> u64 {
+    let mut buf = vec![0; consts::BUFFER_SIZE];
+    let mut total = 0;
+    loop {
+        let n = reader.read(&mut buf).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        writer.write_all(&buf[..n]).await.unwrap();
+        total += n as u64;
+    }
+    writer.shutdown().await.unwrap();
+    total
+}
+
+async fn pooled_copy(reader: OwnedReadHalf, mut writer: OwnedWriteHalf) -> u64 {
+    let mut size = buffers::AdaptiveSize::new();
+    let mut total = 0;
+    loop {
+        let chunks = buffers::read_chunks(&reader, &mut size).await.unwrap();
+        if chunks.is_empty() {
+            break;
+        }
+        buffers::write_chunks(&mut writer, &chunks).await.unwrap();
+        total += chunks.len() as u64;
+    }
+    writer.shutdown().await.unwrap();
+    total
+}
+
+async fn connected_pair(listener: &TcpListener) -> (TcpStream, TcpStream) {
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    (client, server)
+}
+
+// Source -> relay (copy engine) -> sink, for every relay at once
+async fn run(engine: Engine, relays: usize, bytes_per_relay: usize) -> Duration {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+    let mut sources = Vec::new();
+    for _ in 0..relays {
+        let (mut source, relay_in) = connected_pair(&listener).await;
+        let (relay_out, mut sink) = connected_pair(&listener).await;
+        let (reader, _) = relay_in.into_split();
+        let (_, writer) = relay_out.into_split();
+        tasks.push(tokio::spawn(async move {
+            match engine {
+                Engine::Legacy => legacy_copy(reader, writer).await,
+                Engine::Pooled => pooled_copy(reader, writer).await,
+            }
+        }));
+        tasks.push(tokio::spawn(async move {
+            let mut buf = vec![0; 64 * 1024];
+            let mut total = 0;
+            loop {
+                let n = sink.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break total;
+                }
+                total += n as u64;
+            }
+        }));
+        sources.push(tokio::spawn(async move {
+            let data = vec![0x5A; 64 * 1024];
+            for _ in 0..bytes_per_relay / data.len() {
+                source.write_all(&data).await.unwrap();
+            }
+            source.shutdown().await.unwrap();
+            source
+        }));
+    }
+
+    for task in tasks {
+        assert_eq!(task.await.unwrap(), bytes_per_relay as u64);
+    }
+    let elapsed = start.elapsed();
+    for source in sources {
+        source.await.unwrap();
+    }
+    elapsed
+}
+
+#[tokio::main]
+async fn main() {
+    // cargo bench passes --bench, cargo test does not
+    if !std::env::args().any(|arg| arg == "--bench") {
+        for engine in [Engine::Legacy, Engine::Pooled] {
+            run(engine, 4, 1024 * 1024).await;
+        }
+        println!("relay_throughput: ok (run with cargo bench to measure)");
+        return;
+    }
+
+    let total_mib = (RELAYS * BYTES_PER_RELAY) as f64 / (1024.0 * 1024.0);
+    println!(
+        "{} relays, {} MiB each, best of {} rounds",
+        RELAYS,
+        BYTES_PER_RELAY / (1024 * 1024),
+        ROUNDS
+    );
+    for engine in [Engine::Legacy, Engine::Pooled] {
+        let mut best = Duration::MAX;
+        for _ in 0..ROUNDS {
+            best = best.min(run(engine, RELAYS, BYTES_PER_RELAY).await);
+        }
+        println!(
+            "{:>8}: {:>10.1} MiB/s ({:?})",
+            format!("{:?}", engine),
+            total_mib / best.as_secs_f64(),
+            best
+        );
+    }
+    println!(
+        "Buffers kept on the pool after the run: {}",
+        buffers::pool().pooled()
+    );
+}
@@ -1,20 +1,27 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use anyhow::Context;
 use log::{debug, info};
 
-use udstunnel::tunnel::{self, config, consts, event, server, stats};
+use udstunnel::tunnel::{
+    self, admin, client, config, consts, daemon, event, limiter, metrics, server, sessions, stats,
+    throttle,
+};
 
 #[cfg(unix)]
 use tokio::signal::unix::{signal as unix_signal, SignalKind};
 
 use tokio::{
-    select,
+    runtime, select,
     signal::{self},
     time::timeout,
 };
 
-#[tokio::main(flavor = "multi_thread")]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cmd = clap::Command::new("udstunnel")
         .version("5.0.0")
         .author("Adolfo Gómez <dkmaster@dkmon.com>")
@@ -48,6 +55,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .help("get current detailed stats from RUNNING tunnel")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            clap::Arg::new("json")
+                .short('j')
+                .long("json")
+                .help("Output stats as JSON (with --stats or --detailed-stats)")
+                .action(clap::ArgAction::SetTrue),
+        )
         .arg(
             clap::Arg::new("ipv6")
                 .short('6')
@@ -59,6 +73,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let matches = cmd.get_matches();
     let stats = matches.get_flag("stats");
     let detailed_stats = matches.get_flag("detailed-stats");
+    let json = matches.get_flag("json");
     let tunnel = matches.get_flag("tunnel") || (!stats && !detailed_stats);
     let config_file = matches.try_get_one::<String>("config").unwrap();
 
@@ -71,30 +86,213 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .load()
         .unwrap();
 
-    tunnel::log::setup(&config.logfile, &config.loglevel);
+    // Stats client only logs to stderr, the log file belongs to the running tunnel
+    tunnel::log::setup(
+        if tunnel { &config.logfile } else { &None },
+        &config.loglevel,
+        config.logsize,
+        config.lognumber,
+        config.log_format,
+    );
 
     info!("Starting udstunnel v{}", consts::VERSION);
 
     debug!("Config: {:?}", config);
     //println!("{}", cmd.render_long_help());
 
+    if tunnel {
+        run_tunnel(&config)?;
+    } else {
+        // Connect to the running tunnel, on the configured listen address
+        let host = config
+            .listen_address
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let host = match host.parse::<IpAddr>() {
+            Ok(ip) if ip.is_unspecified() => "localhost",
+            _ => host,
+        };
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let report = runtime.block_on(client::request_stats(
+            host,
+            config.listen_port,
+            &config.secret,
+            detailed_stats,
+        ))?;
+        print_stats(&report, detailed_stats, json)?;
+    }
+
+    Ok(())
+}
+
+fn build_runtime(
+    config: &config::Config,
+    single_thread: bool,
+) -> std::io::Result<runtime::Runtime> {
+    if single_thread {
+        runtime::Builder::new_current_thread().enable_all().build()
+    } else {
+        runtime::Builder::new_multi_thread()
+            .worker_threads(config.workers as usize)
+            .enable_all()
+            .build()
+    }
+}
+
+// Waits (with a timeout) for the running relays to finish
+async fn wait_relays(stats: &stats::Stats) -> bool {
+    timeout(Duration::from_secs(8), async {
+        while stats.get_concurrent_connections() > 0 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    })
+    .await
+    .is_ok()
+}
+
+fn run_tunnel(config: &config::Config) -> Result<(), Box<dyn std::error::Error>> {
     let stats = Arc::new(stats::Stats::new());
+    let sessions = Arc::new(sessions::SessionRegistry::new());
+    let limiter = Arc::new(limiter::Limiter::new(config));
+    let bandwidth = Arc::new(throttle::BandwidthLimits::new(config));
 
-    if tunnel {
-        let tunnel = server::TunnelServer::new(&config, stats.clone());
+    // Main runtime runs signals, metrics and admin api, and also the tunnel
+    // unless runtime_per_core is set. In that case, it's single threaded, and every
+    // worker gets its own single threaded runtime and listener (SO_REUSEPORT)
+    let runtime = build_runtime(config, config.runtime_per_core)?;
+    let mut tunnels = Vec::new();
+    if config.runtime_per_core {
+        info!("Starting {} runtimes", config.workers);
+        for _ in 0..config.workers {
+            tunnels.push((
+                Some(build_runtime(config, true)?),
+                server::TunnelServer::new(config, stats.clone()),
+            ));
+        }
+    } else {
+        info!("Starting runtime with {} workers", config.workers);
+        tunnels.push((None, server::TunnelServer::new(config, stats.clone())));
+    }
+
+    // Privileged operations first: bind (maybe a port < 1024), read the key and write the pid file
+    // Listeners are registered on the runtime that will run them
+    for (tunnel_runtime, tunnel) in tunnels.iter_mut() {
+        tunnel_runtime
+            .as_ref()
+            .unwrap_or(&runtime)
+            .block_on(tunnel.listen())?;
+    }
+    let cert_resolvers: Vec<_> = tunnels
+        .iter()
+        .flat_map(|(_, tunnel)| tunnel.cert_resolvers())
+        .collect();
+    let metrics = if config.metrics_port != 0 {
+        let mut metrics = metrics::MetricsServer::new(config, stats.clone());
+        runtime.block_on(metrics.listen())?;
+        Some(metrics)
+    } else {
+        None
+    };
+    let admin = if !config.admin_listen.is_empty() {
+        let mut admin = admin::AdminServer::new(config, sessions.clone());
+        runtime.block_on(admin.listen())?;
+        Some(admin)
+    } else {
+        None
+    };
+    let pidfile = if config.pidfile.is_empty() {
+        None
+    } else {
+        match daemon::PidFile::create(&config.pidfile, &config.user) {
+            Ok(pidfile) => Some(pidfile),
+            Err(e) => {
+                log::warn!("{:?}", e);
+                None
+            }
+        }
+    };
+    if let Err(e) = give_files_to_user(config) {
+        log::warn!("{:?}", e);
+    }
+    daemon::drop_privileges(&config.user)?;
 
+    runtime.block_on(async {
         let stop_event = event::Event::new();
 
         let ctrl_c = signal::ctrl_c();
         #[cfg(unix)]
         let mut terminate = unix_signal(SignalKind::terminate())?;
 
-        let task_stopper = stop_event.clone();
-        let tunnel_task = tokio::spawn(async move {
-            if let Err(e) = tunnel.run(task_stopper).await {
-                info!("Tunnel server error: {:?}", e);
-            }
-        });
+        // SIGHUP reopens the log file, for external log rotation, and reloads the certificate
+        #[cfg(unix)]
+        {
+            let mut hangup = unix_signal(SignalKind::hangup())?;
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    info!("SIGHUP received, reopening log file and reloading certificate");
+                    tunnel::log::reopen();
+                    for cert_resolver in &cert_resolvers {
+                        if let Err(e) = cert_resolver.reload() {
+                            log::error!(
+                                "Error reloading certificate, keeping current one: {:?}",
+                                e
+                            );
+                        }
+                    }
+                }
+            });
+        }
+        #[cfg(not(unix))]
+        drop(cert_resolvers);
+
+        let mut tunnel_tasks = Vec::new();
+        for (tunnel_runtime, tunnel) in tunnels {
+            let tunnel = tunnel
+                .with_sessions(sessions.clone())
+                .with_limiter(limiter.clone())
+                .with_bandwidth(bandwidth.clone());
+            let task_stopper = stop_event.clone();
+            let task = match tunnel_runtime {
+                None => tokio::spawn(async move {
+                    if let Err(e) = tunnel.run(task_stopper).await {
+                        info!("Tunnel server error: {:?}", e);
+                    }
+                }),
+                Some(tunnel_runtime) => {
+                    let stats = stats.clone();
+                    tokio::task::spawn_blocking(move || {
+                        tunnel_runtime.block_on(async move {
+                            if let Err(e) = tunnel.run(task_stopper).await {
+                                info!("Tunnel server error: {:?}", e);
+                            }
+                            // Relays run on this runtime, keep it alive until they finish
+                            wait_relays(&stats).await;
+                        })
+                    })
+                }
+            };
+            tunnel_tasks.push(task);
+        }
+
+        if let Some(metrics) = metrics {
+            let task_stopper = stop_event.clone();
+            tokio::spawn(async move {
+                if let Err(e) = metrics.run(task_stopper).await {
+                    info!("Metrics server error: {:?}", e);
+                }
+            });
+        }
+
+        if let Some(admin) = admin {
+            let task_stopper = stop_event.clone();
+            tokio::spawn(async move {
+                if let Err(e) = admin.run(task_stopper).await {
+                    info!("Admin api error: {:?}", e);
+                }
+            });
+        }
 
         #[cfg(unix)]
         select! {
@@ -118,27 +316,86 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         // Ensure tunnel task is finished
         // While stats get_concurrent_connections is not 0, we wait (with a timeout)
         info!("Waiting for tunnel relay tasks to finish");
-        timeout(Duration::from_secs(8), async {
-            while stats.get_concurrent_connections() > 0 {
-                tokio::time::sleep(Duration::from_secs(1)).await;
-            }
-        })
-        .await
-        .expect("Timeout waiting for concurrent connections to be 0. Force stopping");
+        if !wait_relays(&stats).await {
+            panic!("Timeout waiting for concurrent connections to be 0. Force stopping");
+        }
 
         info!("Waiting for tunnel task to finish");
-        timeout(Duration::from_secs(8), tunnel_task)
-            .await
-            .expect("Tunnel task should never fail")
-            .expect("Timeout waiting for tunnel task. Force stopping");
-    } else {
-        // TODO: Implement stats
-    }
+        for tunnel_task in tunnel_tasks {
+            timeout(Duration::from_secs(8), tunnel_task)
+                .await
+                .expect("Tunnel task should never fail")
+                .expect("Timeout waiting for tunnel task. Force stopping");
+        }
+        Ok::<(), Box<dyn std::error::Error>>(())
+    })?;
 
+    drop(pidfile);
     Ok(())
+}
 
-    // let _ = launch().await?;
+// Log and audit files are opened again after dropping privileges (rotation, SIGHUP, and
+// every audit record), so they must belong to the user the tunnel runs as
+fn give_files_to_user(config: &config::Config) -> anyhow::Result<()> {
+    let mut paths = Vec::new();
+    if let Some(logfile) = &config.logfile {
+        paths.extend(tunnel::log::files(logfile, config.lognumber));
+    }
+    if let Some(audit_file) = &config.audit_file {
+        // Created now, the user may not be allowed to on its directory
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(audit_file)
+            .with_context(|| format!("Error creating audit file {}", audit_file))?;
+        paths.push(audit_file.into());
+    }
+    for path in paths {
+        daemon::give_to_user(&path, &config.user)?;
+    }
+    Ok(())
+}
 
-    // println!("Hello!!");
-    // Ok(())
+fn print_stats(
+    report: &stats::StatsReport,
+    detailed: bool,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if json {
+        if detailed {
+            println!("{}", serde_json::to_string_pretty(report)?);
+        } else {
+            println!("{}", serde_json::to_string_pretty(&report.global)?);
+        }
+        return Ok(());
+    }
+
+    println!(
+        "Concurrent connections: {}",
+        report.global.concurrent_connections
+    );
+    println!("Total connections: {}", report.global.total_connections);
+    println!("Sent bytes: {}", report.global.sent_bytes);
+    println!("Received bytes: {}", report.global.recv_bytes);
+
+    if detailed {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        println!("Sessions: {}", report.sessions.len());
+        for session in &report.sessions {
+            println!(
+                "  {} {} -> {} ticket:{} elapsed:{}s sent:{} recv:{}",
+                session.tunnel_id,
+                session.src,
+                session.dst,
+                session.ticket,
+                now.saturating_sub(session.start),
+                session.sent_bytes,
+                session.recv_bytes
+            );
+        }
+    }
+    Ok(())
 }
@@ -17,12 +17,17 @@ use tokio_rustls::{
 use rustls_native_certs::load_native_certs;
 
 use super::callbacks::TLSClientCallback;
+use super::cert_resolver::{load_certificates, load_private_key};
 use super::noverify::NoVerifySsl;
+use crate::tunnel::config::Password;
 
 pub struct ConnectionBuilder {
     server: String,
     port: u16,
     verify: bool,
+    server_name: Option<String>,
+    // Client certificate (file with the chain) and key files, for mutual TLS
+    client_certificate: Option<(String, String)>,
     connect_callback: Option<Box<dyn TLSClientCallback>>,
 }
 
@@ -32,6 +37,7 @@ impl fmt::Debug for ConnectionBuilder {
             .field("server", &self.server)
             .field("port", &self.port)
             .field("verify", &self.verify)
+            .field("server_name", &self.server_name)
             .finish()
     }
 }
@@ -42,6 +48,8 @@ impl ConnectionBuilder {
             server: String::from(server),
             port,
             verify: true,
+            server_name: None,
+            client_certificate: None,
             connect_callback: None,
         }
     }
@@ -51,6 +59,18 @@ impl ConnectionBuilder {
         self
     }
 
+    /// Server name (SNI) to send, if different from the server we connect to
+    pub fn with_server_name(mut self, server_name: &str) -> Self {
+        self.server_name = Some(server_name.to_string());
+        self
+    }
+
+    /// Client certificate to present, if the server requests it
+    pub fn with_client_certificate(mut self, cert_path: &str, key_path: &str) -> Self {
+        self.client_certificate = Some((cert_path.to_string(), key_path.to_string()));
+        self
+    }
+
     pub fn with_connect_callback<T: TLSClientCallback + 'static>(mut self, callback: T) -> Self {
         self.connect_callback = Some(Box::new(callback));
         self
@@ -64,12 +84,22 @@ impl ConnectionBuilder {
         let mut root_store = RootCertStore::empty();
         root_store.add_parsable_certificates(certs);
 
-        let mut config =
+        let config =
             rustls::ClientConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
                 .with_protocol_versions(ALL_VERSIONS)
                 .unwrap()
-                .with_root_certificates(root_store)
-                .with_no_client_auth();
+                .with_root_certificates(root_store);
+        let mut config = match &self.client_certificate {
+            Some((cert_path, key_path)) => {
+                let certs = load_certificates(cert_path).map_err(Error::other)?;
+                let key = load_private_key(cert_path, key_path, &Password::default())
+                    .map_err(Error::other)?;
+                config
+                    .with_client_auth_cert(certs, key)
+                    .map_err(Error::other)?
+            }
+            None => config.with_no_client_auth(),
+        };
 
         if !self.verify {
             config
@@ -78,17 +108,22 @@ impl ConnectionBuilder {
         }
 
         let connector = TlsConnector::from(Arc::new(config));
-        let server_name = self.server.clone().try_into().unwrap();
-        let mut stream = TcpStream::connect(format!("{}:{}", self.server, self.port))
-            .await
-            .unwrap();
+        // IPv6 literals may come with brackets, that are not valid for name nor address
+        let host = self.server.trim_start_matches('[').trim_end_matches(']');
+        let server_name = self
+            .server_name
+            .clone()
+            .unwrap_or_else(|| host.to_string())
+            .try_into()
+            .map_err(|e| Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let mut stream = TcpStream::connect((host, self.port)).await?;
 
         if let Some(connect_callback) = self.connect_callback {
             connect_callback.process(&mut stream).await?;
         }
 
         let tls_stream: tokio_rustls::client::TlsStream<TcpStream> =
-            connector.connect(server_name, stream).await.unwrap();
+            connector.connect(server_name, stream).await?;
 
         Ok(tls_stream)
     }
@@ -0,0 +1,81 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::{pem::PemObject, CertificateDer, CertificateRevocationListDer},
+    server::{danger::ClientCertVerifier, WebPkiClientVerifier},
+    RootCertStore,
+};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use super::cert_resolver::load_certificates;
+
+/// Verifier of client certificates signed by the CA(s) on `ca_path`, optionally checking
+/// the revocation lists on `crl_path`.
+/// Clients without certificate are allowed to complete the handshake (so stats and test
+/// commands keep working), but presented certificates must be valid.
+/// Is up to the caller to require a certificate where needed
+pub fn verifier(
+    ca_path: &str,
+    crl_path: &str,
+    provider: Arc<CryptoProvider>,
+) -> Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certificates(ca_path)? {
+        roots
+            .add(cert)
+            .with_context(|| format!("Invalid CA certificate in {}", ca_path))?;
+    }
+
+    let mut builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+        .allow_unauthenticated();
+    if !crl_path.is_empty() {
+        let crls = CertificateRevocationListDer::pem_file_iter(crl_path)
+            .with_context(|| format!("Error reading CRL {}", crl_path))?
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Invalid CRL in {}", crl_path))?;
+        builder = builder.with_crls(crls);
+    }
+    builder.build().with_context(|| {
+        format!(
+            "Error creating client certificate verifier from {}",
+            ca_path
+        )
+    })
+}
+
+/// Subject of the certificate, as "C=ES, O=Org, CN=name"
+pub fn subject(cert: &CertificateDer<'_>) -> Option<String> {
+    X509Certificate::from_der(cert.as_ref())
+        .ok()
+        .map(|(_, cert)| cert.subject().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::crypto_provider;
+
+    #[test]
+    fn test_subject() {
+        let cert = load_certificates("tests/certs/client.pem").unwrap();
+        assert_eq!(subject(&cert[0]).unwrap(), "C=ES, O=Me, CN=client");
+    }
+
+    #[test]
+    fn test_verifier() {
+        let provider = Arc::new(crypto_provider::provider(""));
+        let verifier = verifier(
+            "tests/certs/ca.pem",
+            "tests/certs/crl.pem",
+            provider.clone(),
+        )
+        .unwrap();
+        assert!(!verifier.client_auth_mandatory());
+
+        let err =
+            super::verifier("tests/certs/ca.pem", "/non/existing/crl.pem", provider).unwrap_err();
+        assert!(format!("{:#}", err).contains("/non/existing/crl.pem"));
+    }
+}
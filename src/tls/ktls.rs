@@ -0,0 +1,314 @@
+use std::{
+    io, mem,
+    os::fd::{AsRawFd, RawFd},
+};
+
+use rustls::{ConnectionTrafficSecrets, ProtocolVersion};
+use tokio::{io::Interest, net::TcpStream};
+use tokio_rustls::server::TlsStream;
+
+// Alert record type, and level and description of the close_notify alert
+const RECORD_TYPE_ALERT: u8 = 21;
+const ALERT_LEVEL_WARNING: u8 = 1;
+const ALERT_CLOSE_NOTIFY: u8 = 0;
+
+/// Client stream once the handshake is done, with the records handled by the kernel or by rustls
+pub enum Offload {
+    /// Kernel TLS, the socket reads and writes plaintext
+    Kernel(TcpStream),
+    /// Kernel TLS not available for this connection, rustls keeps handling it
+    Userspace(Box<TlsStream<TcpStream>>),
+}
+
+/// Hands the session keys of `stream` to the kernel (kTLS). The server config must have
+/// `enable_secret_extraction` set, and the stream must be flushed, with no data from the client
+/// after the last read.
+/// Falls back to userspace if the kernel has no "tls" module, or the protocol version or cipher
+/// suite are not supported. Once the keys are extracted there is no way back, so later errors
+/// are returned (and the connection must be closed)
+pub fn offload(mut stream: TlsStream<TcpStream>) -> io::Result<Offload> {
+    let (socket, conn) = stream.get_mut();
+    let fd = socket.as_raw_fd();
+    let version = match conn.protocol_version() {
+        Some(ProtocolVersion::TLSv1_2) => libc::TLS_1_2_VERSION,
+        Some(ProtocolVersion::TLSv1_3) => libc::TLS_1_3_VERSION,
+        _ => return Ok(Offload::Userspace(Box::new(stream))),
+    };
+    let supported = conn.negotiated_cipher_suite().is_some_and(|suite| {
+        suite
+            .suite()
+            .as_str()
+            .is_some_and(|name| name.contains("_GCM_") || name.contains("CHACHA20_POLY1305"))
+    });
+    if !supported {
+        log::debug!("Cipher suite not supported by kernel TLS");
+        return Ok(Offload::Userspace(Box::new(stream)));
+    }
+    // Records already read are decrypted now, if any has data it belongs to rustls
+    let state = conn
+        .process_new_packets()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if state.plaintext_bytes_to_read() > 0 || conn.wants_write() {
+        log::debug!("Data pending on TLS buffers, not using kernel TLS");
+        return Ok(Offload::Userspace(Box::new(stream)));
+    }
+    if let Err(e) = setsockopt(fd, libc::SOL_TCP, libc::TCP_ULP, b"tls") {
+        log::debug!("Kernel TLS not available: {}", e);
+        return Ok(Offload::Userspace(Box::new(stream)));
+    }
+
+    let (socket, conn) = stream.into_inner();
+    let secrets = conn.dangerous_extract_secrets().map_err(io::Error::other)?;
+    let (seq, tx) = secrets.tx;
+    CryptoInfo::new(version, seq, tx)?.set(fd, libc::TLS_TX)?;
+    let (seq, rx) = secrets.rx;
+    CryptoInfo::new(version, seq, rx)?.set(fd, libc::TLS_RX)?;
+    Ok(Offload::Kernel(socket))
+}
+
+/// Reads the control record waiting on a kernel TLS socket. Reads (and splices) only return
+/// application data, and fail with EIO (EINVAL on splice) while a control record is next.
+/// Ok if it is a close_notify alert (the client closed the connection), error on any other
+/// (i.e. other alerts, or TLS 1.3 key updates, that are not supported)
+pub fn read_close_notify(socket: &TcpStream) -> io::Result<()> {
+    let mut data = [0u8; 16];
+    // u64 to keep the control messages aligned
+    let mut control = [0u64; 8];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr().cast(),
+        iov_len: data.len(),
+    };
+    // SAFETY: msghdr is plain data, all zeroes is a valid (empty) value
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    // SAFETY: msg points to buffers that live until the call returns
+    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_DONTWAIT) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if n == 0 {
+        return Ok(());
+    }
+    // SAFETY: msg was filled by recvmsg, and the cmsg macros keep inside msg_controllen
+    let record_type = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_TLS
+            || (*cmsg).cmsg_type != libc::TLS_GET_RECORD_TYPE
+        {
+            None
+        } else {
+            Some(*libc::CMSG_DATA(cmsg))
+        }
+    };
+    match record_type {
+        Some(RECORD_TYPE_ALERT) if n >= 2 && data[1] == ALERT_CLOSE_NOTIFY => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsupported TLS record on kernel TLS: {:?}", record_type),
+        )),
+    }
+}
+
+/// Sends a close_notify alert on a kernel TLS socket (writes only send application data)
+pub async fn send_close_notify(socket: &TcpStream) -> io::Result<()> {
+    loop {
+        socket.writable().await?;
+        match socket.try_io(Interest::WRITABLE, || sendmsg_alert(socket.as_raw_fd())) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            res => return res,
+        }
+    }
+}
+
+fn sendmsg_alert(fd: RawFd) -> io::Result<()> {
+    let mut alert = [ALERT_LEVEL_WARNING, ALERT_CLOSE_NOTIFY];
+    // u64 to keep the control messages aligned
+    let mut control = [0u64; 4];
+    let mut iov = libc::iovec {
+        iov_base: alert.as_mut_ptr().cast(),
+        iov_len: alert.len(),
+    };
+    // SAFETY: msghdr is plain data, all zeroes is a valid (empty) value
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    // SAFETY: control has room for one message with one byte of data, and msg points to
+    // buffers that live until sendmsg returns
+    let n = unsafe {
+        msg.msg_controllen = libc::CMSG_SPACE(1) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_TLS;
+        (*cmsg).cmsg_type = libc::TLS_SET_RECORD_TYPE;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(1) as _;
+        *libc::CMSG_DATA(cmsg) = RECORD_TYPE_ALERT;
+        libc::sendmsg(fd, &msg, 0)
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Keys of one direction, as the TLS_TX and TLS_RX socket options expect them
+enum CryptoInfo {
+    AesGcm128(libc::tls12_crypto_info_aes_gcm_128),
+    AesGcm256(libc::tls12_crypto_info_aes_gcm_256),
+    Chacha20Poly1305(libc::tls12_crypto_info_chacha20_poly1305),
+}
+
+impl CryptoInfo {
+    fn new(version: u16, seq: u64, secrets: ConnectionTrafficSecrets) -> io::Result<Self> {
+        let rec_seq = seq.to_be_bytes();
+        // GCM nonces are a fixed salt (4 bytes) followed by the iv, chacha ones are just the iv
+        match secrets {
+            ConnectionTrafficSecrets::Aes128Gcm { key, iv } => {
+                Ok(CryptoInfo::AesGcm128(libc::tls12_crypto_info_aes_gcm_128 {
+                    info: libc::tls_crypto_info {
+                        version,
+                        cipher_type: libc::TLS_CIPHER_AES_GCM_128,
+                    },
+                    iv: to_array(&iv.as_ref()[4..])?,
+                    key: to_array(key.as_ref())?,
+                    salt: to_array(&iv.as_ref()[..4])?,
+                    rec_seq,
+                }))
+            }
+            ConnectionTrafficSecrets::Aes256Gcm { key, iv } => {
+                Ok(CryptoInfo::AesGcm256(libc::tls12_crypto_info_aes_gcm_256 {
+                    info: libc::tls_crypto_info {
+                        version,
+                        cipher_type: libc::TLS_CIPHER_AES_GCM_256,
+                    },
+                    iv: to_array(&iv.as_ref()[4..])?,
+                    key: to_array(key.as_ref())?,
+                    salt: to_array(&iv.as_ref()[..4])?,
+                    rec_seq,
+                }))
+            }
+            ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv } => Ok(
+                CryptoInfo::Chacha20Poly1305(libc::tls12_crypto_info_chacha20_poly1305 {
+                    info: libc::tls_crypto_info {
+                        version,
+                        cipher_type: libc::TLS_CIPHER_CHACHA20_POLY1305,
+                    },
+                    iv: to_array(iv.as_ref())?,
+                    key: to_array(key.as_ref())?,
+                    salt: [],
+                    rec_seq,
+                }),
+            ),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Cipher not supported by kernel TLS",
+            )),
+        }
+    }
+
+    fn set(&self, fd: RawFd, direction: libc::c_int) -> io::Result<()> {
+        match self {
+            CryptoInfo::AesGcm128(info) => setsockopt(fd, libc::SOL_TLS, direction, info),
+            CryptoInfo::AesGcm256(info) => setsockopt(fd, libc::SOL_TLS, direction, info),
+            CryptoInfo::Chacha20Poly1305(info) => setsockopt(fd, libc::SOL_TLS, direction, info),
+        }
+    }
+}
+
+fn to_array<const N: usize>(bytes: &[u8]) -> io::Result<[u8; N]> {
+    bytes.try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid key material length {}, expected {}",
+                bytes.len(),
+                N
+            ),
+        )
+    })
+}
+
+fn setsockopt<T: ?Sized>(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &T,
+) -> io::Result<()> {
+    // SAFETY: value is a valid reference, and its size is passed along
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            (value as *const T).cast(),
+            mem::size_of_val(value) as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::crypto::cipher::{AeadKey, Iv};
+
+    use super::*;
+
+    fn secrets_iv() -> Iv {
+        Iv::from([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12])
+    }
+
+    #[test]
+    fn test_crypto_info_gcm() {
+        let secrets = ConnectionTrafficSecrets::Aes256Gcm {
+            key: AeadKey::from([7; 32]),
+            iv: secrets_iv(),
+        };
+        let Ok(CryptoInfo::AesGcm256(info)) = CryptoInfo::new(libc::TLS_1_3_VERSION, 258, secrets)
+        else {
+            panic!("Expected AES-256-GCM crypto info");
+        };
+        assert_eq!(info.info.version, libc::TLS_1_3_VERSION);
+        assert_eq!(info.info.cipher_type, libc::TLS_CIPHER_AES_GCM_256);
+        assert_eq!(info.salt, [1, 2, 3, 4]);
+        assert_eq!(info.iv, [5, 6, 7, 8, 9, 10, 11, 12]);
+        assert_eq!(info.key, [7; 32]);
+        assert_eq!(info.rec_seq, [0, 0, 0, 0, 0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn test_crypto_info_chacha() {
+        let secrets = ConnectionTrafficSecrets::Chacha20Poly1305 {
+            key: AeadKey::from([7; 32]),
+            iv: secrets_iv(),
+        };
+        let Ok(CryptoInfo::Chacha20Poly1305(info)) =
+            CryptoInfo::new(libc::TLS_1_2_VERSION, 1, secrets)
+        else {
+            panic!("Expected CHACHA20-POLY1305 crypto info");
+        };
+        assert_eq!(info.info.version, libc::TLS_1_2_VERSION);
+        assert_eq!(info.info.cipher_type, libc::TLS_CIPHER_CHACHA20_POLY1305);
+        assert_eq!(info.iv, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+        assert_eq!(info.rec_seq, [0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_crypto_info_invalid_key() {
+        // AES-128 keys are 16 bytes
+        let secrets = ConnectionTrafficSecrets::Aes128Gcm {
+            key: AeadKey::from([7; 32]),
+            iv: secrets_iv(),
+        };
+        let err = CryptoInfo::new(libc::TLS_1_3_VERSION, 0, secrets)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -1,5 +1,10 @@
 pub mod callbacks;
+pub mod cert_resolver;
 pub mod client;
+pub mod client_auth;
 
 pub mod crypto_provider;
+#[cfg(target_os = "linux")]
+pub mod ktls;
 pub mod noverify;
+pub mod sni_resolver;
@@ -0,0 +1,126 @@
+use std::sync::Arc;
+
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+use super::cert_resolver::CertResolver;
+use crate::tunnel::config::VirtualHost;
+
+/// Picks the certificate of the virtual host matching the SNI of the client.
+/// If no SNI is sent, or no virtual host matches, the default certificate is used
+#[derive(Debug)]
+pub struct SniResolver {
+    default: Arc<CertResolver>,
+    virtual_hosts: Vec<(VirtualHost, Arc<CertResolver>)>,
+}
+
+impl SniResolver {
+    pub fn new(default: Arc<CertResolver>) -> Self {
+        SniResolver {
+            default,
+            virtual_hosts: Vec::new(),
+        }
+    }
+
+    pub fn with_virtual_host(
+        mut self,
+        virtual_host: VirtualHost,
+        resolver: Arc<CertResolver>,
+    ) -> Self {
+        self.virtual_hosts.push((virtual_host, resolver));
+        self
+    }
+
+    /// All the certificate resolvers, default first (to reload them)
+    pub fn cert_resolvers(&self) -> Vec<Arc<CertResolver>> {
+        std::iter::once(self.default.clone())
+            .chain(
+                self.virtual_hosts
+                    .iter()
+                    .map(|(_, resolver)| resolver.clone()),
+            )
+            .collect()
+    }
+
+    pub fn resolver_for(&self, server_name: Option<&str>) -> &Arc<CertResolver> {
+        server_name
+            .and_then(|server_name| {
+                self.virtual_hosts
+                    .iter()
+                    .find(|(virtual_host, _)| virtual_host.matches(server_name))
+            })
+            .map(|(_, resolver)| resolver)
+            .unwrap_or(&self.default)
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(
+            self.resolver_for(client_hello.server_name())
+                .certified_key(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tls::crypto_provider, tunnel::config::Password};
+
+    fn resolver(cert: &str, key: &str) -> Arc<CertResolver> {
+        Arc::new(
+            CertResolver::new(
+                cert,
+                key,
+                &Password::default(),
+                Arc::new(crypto_provider::provider("")),
+            )
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_resolver_for_server_name() {
+        let default = resolver("tests/certs/cert.pem", "tests/certs/key.pem");
+        let customer = resolver("tests/certs/cert2.pem", "tests/certs/key2.pem");
+        let sni = SniResolver::new(default.clone()).with_virtual_host(
+            VirtualHost {
+                name: "customer".into(),
+                server_names: vec!["tunnel.customer.com".into(), "*.customer.org".into()],
+                ssl_certificate: "tests/certs/cert2.pem".into(),
+                ssl_certificate_key: "tests/certs/key2.pem".into(),
+                ssl_password: Password::default(),
+                uds_server: String::new(),
+                uds_token: String::new(),
+            },
+            customer.clone(),
+        );
+
+        assert!(Arc::ptr_eq(
+            sni.resolver_for(Some("tunnel.customer.com")),
+            &customer
+        ));
+        assert!(Arc::ptr_eq(
+            sni.resolver_for(Some("TUNNEL.Customer.com")),
+            &customer
+        ));
+        assert!(Arc::ptr_eq(
+            sni.resolver_for(Some("a.customer.org")),
+            &customer
+        ));
+        assert!(Arc::ptr_eq(
+            sni.resolver_for(Some("customer.org")),
+            &default
+        ));
+        assert!(Arc::ptr_eq(
+            sni.resolver_for(Some("a.b.customer.org")),
+            &default
+        ));
+        assert!(Arc::ptr_eq(sni.resolver_for(Some("other.com")), &default));
+        assert!(Arc::ptr_eq(sni.resolver_for(None), &default));
+        assert_eq!(sni.cert_resolvers().len(), 2);
+    }
+}
@@ -0,0 +1,257 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+
+use super::{config, event, http, sessions};
+
+const CONTENT_TYPE: &str = "application/json";
+
+/// Admin REST api, to list and terminate running relays.
+/// Listens on a loopback address (`host:port`) or on a unix socket (`unix:/path/to/socket`)
+///
+/// * `GET /sessions` lists the running relays
+/// * `GET /sessions/<tunnel_id>` gets a running relay
+/// * `DELETE /sessions/<tunnel_id or notify ticket>` terminates a running relay
+pub struct AdminServer {
+    pub config: config::Config,
+    pub sessions: Arc<sessions::SessionRegistry>,
+    // Bound listener, set by `listen`
+    listener: Option<Listener>,
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, String), // Path of the socket, removed on stop
+}
+
+impl AdminServer {
+    pub fn new(config: &config::Config, sessions: Arc<sessions::SessionRegistry>) -> Self {
+        AdminServer {
+            config: config.clone(),
+            sessions,
+            listener: None,
+        }
+    }
+
+    /// Binds the listener, so it can be done before dropping privileges.
+    /// If not called, `run` does it
+    pub async fn listen(&mut self) -> Result<()> {
+        if self.listener.is_some() {
+            return Ok(());
+        }
+        if let Some(path) = self.config.admin_listen.strip_prefix("unix:") {
+            #[cfg(unix)]
+            {
+                self.listener = Some(bind_unix(path)?);
+                return Ok(());
+            }
+            #[cfg(not(unix))]
+            anyhow::bail!("Unix sockets are not supported on this platform: {}", path);
+        }
+
+        let address: SocketAddr = self
+            .config
+            .admin_listen
+            .parse()
+            .context("Invalid admin_listen address")?;
+        // Admin api has no authentication, so never expose it outside the host
+        if !address.ip().is_loopback() {
+            anyhow::bail!(
+                "Admin api can only listen on a loopback address, not {}",
+                address
+            );
+        }
+
+        log::info!("Admin api running on {}", address);
+
+        self.listener = Some(Listener::Tcp(TcpListener::bind(address).await?));
+        Ok(())
+    }
+
+    pub async fn run(mut self, stop_event: event::Event) -> Result<()> {
+        self.listen().await?;
+        match self.listener.take().context("Listener not bound")? {
+            Listener::Tcp(listener) => loop {
+                let stream;
+                let check_stop_event = stop_event.clone();
+                tokio::select! {
+                    _ = check_stop_event => {
+                        break;
+                    }
+                    listener = listener.accept() => {
+                        stream = listener?.0;
+                    }
+                };
+                self.spawn_serve(stream);
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => {
+                self.run_unix(listener, &path, stop_event).await?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    async fn run_unix(
+        &self,
+        listener: tokio::net::UnixListener,
+        path: &str,
+        stop_event: event::Event,
+    ) -> Result<()> {
+        loop {
+            let stream;
+            let check_stop_event = stop_event.clone();
+            tokio::select! {
+                _ = check_stop_event => {
+                    break;
+                }
+                listener = listener.accept() => {
+                    stream = listener?.0;
+                }
+            };
+            self.spawn_serve(stream);
+        }
+        remove_socket(path).unwrap_or_default();
+        Ok(())
+    }
+
+    fn spawn_serve<S>(&self, stream: S)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let sessions = self.sessions.clone();
+        let read_timeout = self.config.command_timeout;
+        tokio::spawn(async move {
+            if let Err(e) = serve(stream, &sessions, read_timeout).await {
+                log::debug!("ADMIN error: {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &str) -> Result<Listener> {
+    // A stale socket from a previous run would make bind fail
+    remove_socket(path).context("Error removing stale admin socket")?;
+    // Only its owner (the user that started the tunnel) can use the admin api.
+    // The socket is created with that mode, so it is never reachable by others
+    let previous = unsafe { libc::umask(0o177) };
+    let listener = tokio::net::UnixListener::bind(path);
+    unsafe { libc::umask(previous) };
+    let listener = listener?;
+
+    log::info!("Admin api running on unix:{}", path);
+    Ok(Listener::Unix(listener, path.to_string()))
+}
+
+// Removes the unix socket at `path`, if any. Anything else is never removed (we may be root)
+#[cfg(unix)]
+fn remove_socket(path: &str) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => Ok(std::fs::remove_file(path)?),
+        Ok(_) => anyhow::bail!("{} exists and is not a socket", path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn serve<S>(
+    mut stream: S,
+    sessions: &sessions::SessionRegistry,
+    read_timeout: Duration,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = http::read_request(&mut stream, read_timeout).await?;
+    log::debug!("ADMIN {} {}", request.method, request.path);
+    let (status, body) = handle(&request.method, &request.path, sessions);
+    http::write_response(&mut stream, status, CONTENT_TYPE, &body).await
+}
+
+fn error_body(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+fn handle(
+    method: &str,
+    path: &str,
+    sessions: &sessions::SessionRegistry,
+) -> (&'static str, String) {
+    let path = path.trim_end_matches('/');
+    if path == "/sessions" {
+        return match method {
+            "GET" => (
+                "200 OK",
+                serde_json::to_string(&sessions.info()).unwrap_or_default(),
+            ),
+            _ => ("405 Method Not Allowed", error_body("Method not allowed")),
+        };
+    }
+
+    let Some(id) = path
+        .strip_prefix("/sessions/")
+        .filter(|id| !id.is_empty() && !id.contains('/'))
+    else {
+        return ("404 Not Found", error_body("Not found"));
+    };
+
+    let session = match method {
+        "GET" => sessions.get(id).map(|s| s.info()),
+        "DELETE" => sessions.terminate(id),
+        _ => return ("405 Method Not Allowed", error_body("Method not allowed")),
+    };
+    match session {
+        Some(info) => ("200 OK", serde_json::to_string(&info).unwrap_or_default()),
+        None => ("404 Not Found", error_body("Session not found")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::stats;
+
+    #[test]
+    fn test_handle_routes() {
+        let registry = sessions::SessionRegistry::new();
+        registry.register(sessions::Session::new(
+            "tunnel-1",
+            "127.0.0.1:1234",
+            "10.0.0.1:3389",
+            "abcdefghijklmnopqrstuvwxyz",
+            Arc::new(stats::Stats::new()),
+        ));
+
+        let (status, body) = handle("GET", "/sessions", &registry);
+        assert_eq!(status, "200 OK");
+        assert!(body.contains("\"tunnel_id\":\"tunnel-1\""));
+
+        assert_eq!(handle("GET", "/sessions/tunnel-1/", &registry).0, "200 OK");
+        assert_eq!(
+            handle("GET", "/sessions/other", &registry).0,
+            "404 Not Found"
+        );
+        assert_eq!(handle("GET", "/other", &registry).0, "404 Not Found");
+        assert_eq!(
+            handle("POST", "/sessions", &registry).0,
+            "405 Method Not Allowed"
+        );
+        assert_eq!(
+            handle("DELETE", "/sessions/tunnel-1", &registry).0,
+            "200 OK"
+        );
+        assert_eq!(
+            handle("DELETE", "/sessions/other", &registry).0,
+            "404 Not Found"
+        );
+    }
+}
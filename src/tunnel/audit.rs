@@ -0,0 +1,99 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// One record of the session audit log, written once a relay is finished
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub event: String,
+    pub tunnel_id: String,
+    pub src: String,
+    pub dst: String,
+    pub ticket: String, // Only the prefix of the ticket
+    pub start: u64,     // Unix timestamp
+    pub end: u64,       // Unix timestamp
+    pub duration: u64,  // Seconds
+    pub sent_bytes: u64,
+    pub recv_bytes: u64,
+}
+
+/// Append only file of completed relays, one json record per line
+#[derive(Debug)]
+pub struct AuditLog {
+    path: String,
+    lock: Mutex<()>, // Serializes writes, so records never get interleaved
+}
+
+impl AuditLog {
+    pub fn new(path: &str) -> Self {
+        AuditLog {
+            path: path.to_string(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Appends the record. The file is opened on every write, so it can be moved away
+    /// at any time (external rotation) without losing records
+    pub fn append(&self, record: &AuditRecord) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+
+        let _guard = self
+            .lock
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Error locking the audit log"))?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+pub fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_log_appends_jsonl() {
+        let path =
+            std::env::temp_dir().join(format!("udstunnel-audit-{}.log", uuid::Uuid::new_v4()));
+        let audit = AuditLog::new(&path.to_string_lossy());
+        for tunnel_id in ["tunnel-1", "tunnel-2"] {
+            audit
+                .append(&AuditRecord {
+                    event: "terminated".into(),
+                    tunnel_id: tunnel_id.into(),
+                    sent_bytes: 10,
+                    recv_bytes: 20,
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let records = content
+            .lines()
+            .map(|l| serde_json::from_str::<AuditRecord>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].tunnel_id, "tunnel-1");
+        assert_eq!(records[1].tunnel_id, "tunnel-2");
+        assert_eq!(records[1].recv_bytes, 20);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+use super::{sessions, udsapi};
+
+/// Destinations in the order they must be tried, following the balance sent by the broker
+pub fn candidates(
+    destinations: Vec<udsapi::Destination>,
+    balance: udsapi::Balance,
+    sessions: &sessions::SessionRegistry,
+) -> Vec<udsapi::Destination> {
+    match balance {
+        udsapi::Balance::Ordered => destinations,
+        udsapi::Balance::Weighted => weighted(destinations, random),
+        udsapi::Balance::LeastConnections => {
+            let mut destinations: Vec<(usize, udsapi::Destination)> = destinations
+                .into_iter()
+                .map(|dst| (sessions.count_by_dst(&dst.address()), dst))
+                .collect();
+            // Stable, so ties keep the broker order
+            destinations.sort_by_key(|(connections, _)| *connections);
+            destinations.into_iter().map(|(_, dst)| dst).collect()
+        }
+    }
+}
+
+// Random order, where the chance of going first is proportional to the weight.
+// Weight 0 ones go last, in the broker order
+fn weighted(
+    destinations: Vec<udsapi::Destination>,
+    mut random: impl FnMut() -> u64,
+) -> Vec<udsapi::Destination> {
+    let (mut weighted, unweighted): (Vec<_>, Vec<_>) = destinations
+        .into_iter()
+        .partition(|dst| dst.weight.unwrap_or(1) > 0);
+    let mut sorted = Vec::with_capacity(weighted.len() + unweighted.len());
+    while !weighted.is_empty() {
+        let total: u64 = weighted
+            .iter()
+            .map(|dst| dst.weight.unwrap_or(1) as u64)
+            .sum();
+        let mut point = random() % total;
+        let index = weighted
+            .iter()
+            .position(|dst| {
+                let weight = dst.weight.unwrap_or(1) as u64;
+                if point < weight {
+                    return true;
+                }
+                point -= weight;
+                false
+            })
+            .unwrap_or_default();
+        sorted.push(weighted.remove(index));
+    }
+    sorted.extend(unweighted);
+    sorted
+}
+
+// Random enough to spread the load, without depending on a random number generator crate
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::tunnel::stats;
+
+    fn destination(host: &str, weight: Option<u32>) -> udsapi::Destination {
+        udsapi::Destination {
+            host: host.to_string(),
+            port: 3389,
+            weight,
+        }
+    }
+
+    fn hosts(destinations: &[udsapi::Destination]) -> Vec<&str> {
+        destinations.iter().map(|dst| dst.host.as_str()).collect()
+    }
+
+    #[test]
+    fn test_ticket_destinations() {
+        // Single destination, as always
+        let response: udsapi::UdsTicketResponse =
+            serde_json::from_str(r#"{"host": "10.0.0.1", "port": 3389, "notify": "n"}"#).unwrap();
+        assert_eq!(response.destinations(), [destination("10.0.0.1", None)]);
+        assert_eq!(response.balance, None);
+
+        let response: udsapi::UdsTicketResponse = serde_json::from_str(
+            r#"{"notify": "n", "balance": "least_connections", "destinations": [
+                {"host": "10.0.0.1", "port": 3389},
+                {"host": "10.0.0.2", "port": 3389, "weight": 3}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            response.destinations(),
+            [
+                destination("10.0.0.1", None),
+                destination("10.0.0.2", Some(3))
+            ]
+        );
+        assert_eq!(response.balance, Some(udsapi::Balance::LeastConnections));
+
+        // Unknown balances are ordered
+        let response: udsapi::UdsTicketResponse =
+            serde_json::from_str(r#"{"host": "h", "port": 1, "notify": "n", "balance": "new"}"#)
+                .unwrap();
+        assert_eq!(response.balance, Some(udsapi::Balance::Ordered));
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let registry = sessions::SessionRegistry::new();
+        for (id, dst) in [("1", "a:3389"), ("2", "a:3389"), ("3", "b:3389")] {
+            registry.register(sessions::Session::new(
+                id,
+                "127.0.0.1:1234",
+                dst,
+                "ticket",
+                Arc::new(stats::Stats::new()),
+            ));
+        }
+        let destinations = vec![
+            destination("a", None),
+            destination("b", None),
+            destination("c", None),
+            destination("d", None),
+        ];
+        let sorted = candidates(
+            destinations.clone(),
+            udsapi::Balance::LeastConnections,
+            &registry,
+        );
+        assert_eq!(hosts(&sorted), ["c", "d", "b", "a"]);
+        let sorted = candidates(destinations, udsapi::Balance::Ordered, &registry);
+        assert_eq!(hosts(&sorted), ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn test_weighted() {
+        let destinations = vec![
+            destination("a", Some(1)),
+            destination("b", Some(0)),
+            destination("c", Some(3)),
+            destination("d", None),
+        ];
+        // Total weight is 5: 0 is "a", 1 to 3 "c", 4 "d"
+        let sorted = weighted(destinations.clone(), || 2);
+        assert_eq!(hosts(&sorted), ["c", "a", "d", "b"]);
+        let sorted = weighted(destinations.clone(), || 4);
+        assert_eq!(hosts(&sorted), ["d", "a", "c", "b"]);
+
+        // Heavier ones go first more often
+        let mut first = std::collections::HashMap::new();
+        for _ in 0..1000 {
+            let sorted = weighted(destinations.clone(), random);
+            *first.entry(sorted[0].host.clone()).or_insert(0) += 1;
+            assert_eq!(sorted.len(), 4);
+            assert_eq!(sorted[3].host, "b");
+        }
+        assert!(
+            first["c"] > first["a"] && first["c"] > first["d"],
+            "{:?}",
+            first
+        );
+    }
+}
@@ -0,0 +1,305 @@
+use std::{
+    io::{self, IoSlice},
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::tcp::OwnedReadHalf,
+};
+
+use super::consts;
+
+// Buffer sizes are powers of two, from MIN_BUFFER_SIZE to MAX_BUFFER_SIZE
+const SIZE_CLASSES: usize =
+    (consts::MAX_BUFFER_SIZE / consts::MIN_BUFFER_SIZE).trailing_zeros() as usize + 1;
+// Free buffers kept per size class, the rest are released to the allocator
+const MAX_POOLED_PER_CLASS: usize = 1024;
+// Consecutive reads using less than a quarter of the buffer before shrinking it
+const SHRINK_AFTER: u32 = 8;
+// Buffers filled by a single read burst, written together with one vectored write
+const MAX_CHUNKS: usize = 4;
+
+static POOL: BufferPool = BufferPool::new(MAX_POOLED_PER_CLASS);
+
+/// Pool of the buffers used by the relays, shared by the whole process
+pub fn pool() -> &'static BufferPool {
+    &POOL
+}
+
+/// Free buffers by size class, so relays reuse the memory instead of allocating their own
+#[derive(Debug)]
+pub struct BufferPool {
+    classes: [Mutex<Vec<Vec<u8>>>; SIZE_CLASSES],
+    max_per_class: usize,
+}
+
+impl BufferPool {
+    pub const fn new(max_per_class: usize) -> Self {
+        BufferPool {
+            classes: [const { Mutex::new(Vec::new()) }; SIZE_CLASSES],
+            max_per_class,
+        }
+    }
+
+    /// A buffer of at least `size` bytes (up to MAX_BUFFER_SIZE), back to the pool on drop
+    pub fn get(&self, size: usize) -> PooledBuffer<'_> {
+        let class = size_class(size);
+        let buf = self.classes[class]
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| vec![0; consts::MIN_BUFFER_SIZE << class]);
+        PooledBuffer { buf, pool: self }
+    }
+
+    /// Free buffers currently on the pool
+    pub fn pooled(&self) -> usize {
+        self.classes
+            .iter()
+            .map(|class| class.lock().unwrap().len())
+            .sum()
+    }
+
+    fn put(&self, buf: Vec<u8>) {
+        let mut class = self.classes[size_class(buf.len())].lock().unwrap();
+        if class.len() < self.max_per_class {
+            class.push(buf);
+        }
+    }
+}
+
+fn size_class(size: usize) -> usize {
+    let size = size.clamp(consts::MIN_BUFFER_SIZE, consts::MAX_BUFFER_SIZE);
+    (size.next_power_of_two() / consts::MIN_BUFFER_SIZE).trailing_zeros() as usize
+}
+
+/// Buffer taken from a `BufferPool`
+#[derive(Debug)]
+pub struct PooledBuffer<'a> {
+    buf: Vec<u8>,
+    pool: &'a BufferPool,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buf));
+    }
+}
+
+/// Size of the next read of a relay direction, following its traffic:
+/// doubles when a read fills the buffer (bulk transfers), and halves after
+/// several reads using less than a quarter of it (interactive traffic)
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptiveSize {
+    size: usize,
+    small_reads: u32,
+}
+
+impl AdaptiveSize {
+    pub fn new() -> Self {
+        AdaptiveSize {
+            size: consts::MIN_BUFFER_SIZE,
+            small_reads: 0,
+        }
+    }
+
+    pub fn get(&self) -> usize {
+        self.size
+    }
+
+    /// Adjusts the size after a read of `n` bytes
+    pub fn record(&mut self, n: usize) {
+        if n >= self.size {
+            self.size = (self.size * 2).min(consts::MAX_BUFFER_SIZE);
+            self.small_reads = 0;
+        } else if n <= self.size / 4 && self.size > consts::MIN_BUFFER_SIZE {
+            self.small_reads += 1;
+            if self.small_reads >= SHRINK_AFTER {
+                self.size /= 2;
+                self.small_reads = 0;
+            }
+        } else {
+            self.small_reads = 0;
+        }
+    }
+}
+
+impl Default for AdaptiveSize {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Data read in a single burst, on one or more pooled buffers
+#[derive(Debug, Default)]
+pub struct Chunks {
+    chunks: Vec<(PooledBuffer<'static>, usize)>,
+}
+
+impl Chunks {
+    /// Total bytes read, 0 means end of stream
+    pub fn len(&self) -> usize {
+        self.chunks.iter().map(|(_, n)| n).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Waits for data on `reader`, and reads all the data available (up to MAX_CHUNKS buffers).
+/// No buffer is taken from the pool while waiting, so idle relays hold no memory for it
+pub async fn read_chunks(reader: &OwnedReadHalf, size: &mut AdaptiveSize) -> io::Result<Chunks> {
+    let mut chunks = Chunks::default();
+    loop {
+        reader.readable().await?;
+        // Readiness can be a false positive, keep waiting if nothing was read
+        while chunks.chunks.len() < MAX_CHUNKS {
+            let mut buf = pool().get(size.get());
+            match reader.try_read(&mut buf) {
+                Ok(0) => return Ok(chunks),
+                Ok(n) => {
+                    size.record(n);
+                    let full = n == buf.len();
+                    chunks.chunks.push((buf, n));
+                    if !full {
+                        // Short read, nothing more available for now
+                        return Ok(chunks);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        if !chunks.chunks.is_empty() {
+            return Ok(chunks);
+        }
+    }
+}
+
+/// Writes all the chunks, using vectored writes if the writer supports them
+pub async fn write_chunks<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    chunks: &Chunks,
+) -> io::Result<()> {
+    if let [(buf, n)] = chunks.chunks.as_slice() {
+        return writer.write_all(&buf[..*n]).await;
+    }
+    let mut slices: Vec<IoSlice> = chunks
+        .chunks
+        .iter()
+        .map(|(buf, n)| IoSlice::new(&buf[..*n]))
+        .collect();
+    let mut slices = slices.as_mut_slice();
+    while !slices.is_empty() {
+        let written = writer.write_vectored(slices).await?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut slices, written);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    #[test]
+    fn test_size_class() {
+        assert_eq!(size_class(0), 0);
+        assert_eq!(size_class(consts::MIN_BUFFER_SIZE), 0);
+        assert_eq!(size_class(consts::MIN_BUFFER_SIZE + 1), 1);
+        assert_eq!(size_class(consts::MAX_BUFFER_SIZE), SIZE_CLASSES - 1);
+        assert_eq!(size_class(usize::MAX / 2), SIZE_CLASSES - 1);
+    }
+
+    #[test]
+    fn test_pool_reuses_buffers() {
+        let pool = BufferPool::new(1);
+        let first = pool.get(10000);
+        assert_eq!(first.len(), 16 * 1024);
+        let first_ptr = first.as_ptr();
+        let second = pool.get(10000);
+        drop(first);
+        // Only one is kept
+        drop(second);
+        assert_eq!(pool.pooled(), 1);
+        assert_eq!(pool.get(16 * 1024).as_ptr(), first_ptr);
+        // Other sizes are on its own class
+        assert_eq!(pool.get(100).len(), consts::MIN_BUFFER_SIZE);
+    }
+
+    #[test]
+    fn test_adaptive_size() {
+        let mut size = AdaptiveSize::new();
+        assert_eq!(size.get(), consts::MIN_BUFFER_SIZE);
+        // Bulk transfer, grows up to the max
+        for _ in 0..10 {
+            size.record(size.get());
+        }
+        assert_eq!(size.get(), consts::MAX_BUFFER_SIZE);
+        // A few small reads are not enough to shrink
+        for _ in 0..SHRINK_AFTER - 1 {
+            size.record(10);
+        }
+        size.record(consts::MAX_BUFFER_SIZE / 2);
+        for _ in 0..SHRINK_AFTER - 1 {
+            size.record(10);
+        }
+        assert_eq!(size.get(), consts::MAX_BUFFER_SIZE);
+        size.record(10);
+        assert_eq!(size.get(), consts::MAX_BUFFER_SIZE / 2);
+        // Never below the min
+        for _ in 0..100 {
+            size.record(0);
+        }
+        assert_eq!(size.get(), consts::MIN_BUFFER_SIZE);
+    }
+
+    #[tokio::test]
+    async fn test_read_write_chunks() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut source = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (relay, _) = listener.accept().await.unwrap();
+        let (reader, _writer) = relay.into_split();
+
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        source.write_all(&data).await.unwrap();
+        source.shutdown().await.unwrap();
+
+        let mut size = AdaptiveSize::new();
+        let mut received = Vec::new();
+        loop {
+            let chunks = read_chunks(&reader, &mut size).await.unwrap();
+            if chunks.is_empty() {
+                break;
+            }
+            assert!(chunks.chunks.len() <= MAX_CHUNKS);
+            write_chunks(&mut received, &chunks).await.unwrap();
+        }
+        assert_eq!(received, data);
+        // Data came in bulk, so buffers grew
+        assert!(size.get() > consts::MIN_BUFFER_SIZE);
+    }
+}
@@ -1,8 +1,8 @@
-use std::time::Duration;
+use std::{str::FromStr, time::Duration};
 
 use async_trait::async_trait;
 use tokio::{
-    io::{self, AsyncWriteExt},
+    io::{self, AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
     time::timeout,
 };
@@ -13,7 +13,7 @@ use log::debug;
 
 use crate::{
     tls::{callbacks::TLSClientCallback, client::ConnectionBuilder},
-    tunnel::consts,
+    tunnel::{consts, stats, types},
 };
 
 struct UDSClientConnectionCB {}
@@ -35,7 +35,7 @@ pub async fn connect(
     port: u16,
     verify_ssl: bool,
 ) -> io::Result<TlsStream<TcpStream>> {
-    match timeout(
+    timeout(
         Duration::from_secs(8),
         ConnectionBuilder::new(tunnel_server, port)
             .with_connect_callback(UDSClientConnectionCB {})
@@ -43,10 +43,68 @@ pub async fn connect(
             .connect(),
     )
     .await
-    {
-        Ok(conn) => conn,
-        Err(e) => {
-            panic!("Error connecting to server: {:?}", e);
+    .unwrap_or_else(|_| {
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("Timeout connecting to {}:{}", tunnel_server, port),
+        ))
+    })
+}
+
+/// Connects to a running tunnel server and requests its stats.
+/// If `detailed` is true, `INFO` is requested (global stats plus running relays), else `STAT`.
+/// `secret` is the already hashed secret, as stored on `Config::secret`
+pub async fn request_stats(
+    tunnel_server: &str,
+    port: u16,
+    secret: &str,
+    detailed: bool,
+) -> io::Result<stats::StatsReport> {
+    let command = format!(
+        "{}{}",
+        if detailed {
+            consts::COMMAND_INFO
+        } else {
+            consts::COMMAND_STATS
+        },
+        secret
+    );
+    // Ensure what we are about to send is a valid stats command
+    if !matches!(
+        types::Command::from_str(&command),
+        Ok(types::Command::Stats(_) | types::Command::Info(_))
+    ) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Invalid secret for stats command",
+        ));
+    }
+
+    let mut stream = connect(tunnel_server, port, false).await?;
+    stream.write_all(command.as_bytes()).await?;
+
+    // Server closes the connection after sending the response
+    let mut response = Vec::new();
+    let mut buf = [0u8; consts::BUFFER_SIZE];
+    loop {
+        match stream.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => response.extend_from_slice(&buf[..n]),
+            // Some servers close without close_notify, keep what we have
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
         }
     }
+    let response = String::from_utf8_lossy(&response);
+    debug!("Stats response: {}", response);
+
+    if response.starts_with(consts::RESPONSE_FORBIDDEN) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Stats request forbidden by tunnel server",
+        ));
+    }
+
+    stats::StatsReport::from_str(&response)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
@@ -1,7 +1,11 @@
-use std::time::Duration;
+use std::{net::IpAddr, time::Duration};
+
+use ipnet::IpNet;
 
 use sha2::{Digest, Sha256};
 
+use super::{log::LogFormat, proxy_protocol::ProxyProtocol};
+
 /// The `ConfigLoader` struct is responsible for loading and managing the configuration
 /// for the UDS tunnel application. It provides methods to set various configuration
 /// parameters and load the configuration from a file or environment variables.
@@ -48,19 +52,33 @@ pub struct Config {
     pub logfile: Option<String>,
     pub logsize: u32,
     pub lognumber: u32,
+    pub log_format: LogFormat,
+    // Append only file with one json record per finished relay
+    pub audit_file: Option<String>,
 
     pub listen_address: String,
     pub listen_port: u16,
 
     pub ipv6: bool,
 
+    // PROXY protocol (v1/v2) header before the handshake, only trusted from proxy_trusted peers
+    pub proxy_protocol: ProxyProtocol,
+    pub proxy_trusted: NetworkList,
+
     pub workers: u8,
+    // One single threaded runtime per worker, each one with its own SO_REUSEPORT listener
+    pub runtime_per_core: bool,
 
     pub ssl_min_tls_version: String, // Valid values are 1.2, 1.3 (1.0 and 1.1 are not supported)
     pub ssl_certificate: String,
     pub ssl_certificate_key: String,
-    //pub ssl_password: String,  // Maybe supported in the future
+    pub ssl_password: Password, // Passphrase of an encrypted (PKCS#8) private key
     pub ssl_ciphers: String,
+    // If set, OPEN requires a client certificate signed by this CA (and not revoked on ssl_client_crl)
+    pub ssl_client_ca: String,
+    pub ssl_client_crl: String,
+    // Hand the relay encryption to the kernel (Linux kTLS) when the cipher suite allows it
+    pub ssl_ktls: bool,
 
     pub uds_server: String,
     pub uds_token: String,
@@ -69,13 +87,256 @@ pub struct Config {
 
     pub handshake_timeout: Duration,
     pub command_timeout: Duration,
+    // Time to connect to the destination server (trying all its addresses), before ERROR_CONNECT
+    pub connect_timeout: Duration,
+    // Relays are closed after idle_timeout without data, or max_session_duration since open.
+    // Zero means no limit. Both can be overridden per ticket by the broker
+    pub idle_timeout: Duration,
+    pub max_session_duration: Duration,
+    // Bandwidth limits in bytes per second, for all relays together and for each one. 0 means no
+    // limit. Upload is client to server, download server to client. The broker can override the
+    // per session ones on the ticket
+    pub bandwidth_upload: u64,
+    pub bandwidth_download: u64,
+    pub session_bandwidth_upload: u64,
+    pub session_bandwidth_download: u64,
 
     pub secret: String,
-    pub allow: Vec<String>,
+    pub allow: NetworkList,
+
+    // Per source IP token bucket on connections and OPEN commands. Rate 0 means no limit
+    pub rate_limit: f64, // Tokens per second
+    pub rate_limit_burst: u32,
+    // Ban of sources with ban_failures invalid tickets inside ban_window. 0 means no bans
+    pub ban_failures: u32,
+    pub ban_window: Duration,
+    pub ban_time: Duration,
+    // Concurrent connections (pending and relaying), globally and per source IP. 0 means no limit
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+
+    // Prometheus metrics (plain http) listener. Port 0 means disabled
+    pub metrics_address: String,
+    pub metrics_port: u16,
+
+    // Admin api listener, loopback "host:port" or "unix:/path". Empty means disabled
+    pub admin_listen: String,
+
+    // Virtual hosts, selected by SNI. The global settings are used if no one matches
+    pub virtual_hosts: Vec<VirtualHost>,
     // Not used on rust
     // use_uvloop: bool
 }
 
+/// Password that is not shown on debug output (the configuration is logged on debug level)
+#[derive(Clone, Default, PartialEq)]
+pub struct Password(String);
+
+impl Password {
+    pub fn new(password: &str) -> Self {
+        Password(password.to_string())
+    }
+
+    /// Password from the configured value, that can be:
+    ///   * `file:/path/to/file`: The password is the content of the file
+    ///   * `credential:name`: The password is the systemd credential `name` (LoadCredential=)
+    ///   * Anything else: The password itself
+    pub fn resolve(value: &str) -> Result<Self, config::ConfigError> {
+        let path = if let Some(path) = value.strip_prefix("file:") {
+            std::path::PathBuf::from(path)
+        } else if let Some(name) = value.strip_prefix("credential:") {
+            let dir = std::env::var("CREDENTIALS_DIRECTORY").map_err(|_| {
+                config::ConfigError::Message(format!(
+                    "Credential {} requested for ssl_password, but CREDENTIALS_DIRECTORY is not set",
+                    name
+                ))
+            })?;
+            std::path::Path::new(&dir).join(name)
+        } else {
+            return Ok(Password::new(value));
+        };
+        let password = std::fs::read_to_string(&path).map_err(|e| {
+            config::ConfigError::Message(format!(
+                "Error reading ssl_password from {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        // Files usually end with a new line, that is not part of the password
+        Ok(Password::new(password.trim_end_matches(['\r', '\n'])))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl std::fmt::Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "\"\"")
+        } else {
+            write!(f, "\"********\"")
+        }
+    }
+}
+
+/// List of networks (v4 and v6 CIDRs, or single hosts), i.e. "10.0.0.0/8, 192.168.1.5, fd00::/8".
+/// IPv4-mapped IPv6 addresses (::ffff:a.b.c.d) are treated as their IPv4 counterparts
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetworkList(Vec<IpNet>);
+
+impl NetworkList {
+    /// Parses a comma separated list. `name` is the setting, for the error message
+    pub fn parse(name: &str, value: &str) -> Result<Self, config::ConfigError> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .map(canonical_network)
+                    .map_err(|_| {
+                        config::ConfigError::Message(format!(
+                            "Invalid network \"{}\" on {}",
+                            entry, name
+                        ))
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(NetworkList)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0.iter().any(|network| network.contains(&ip))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn networks(&self) -> &[IpNet] {
+        &self.0
+    }
+}
+
+// ::ffff:0:0/96 and smaller are IPv4 networks
+fn canonical_network(network: IpNet) -> IpNet {
+    match network {
+        IpNet::V6(v6) if v6.prefix_len() >= 96 => match v6.addr().to_ipv4_mapped() {
+            Some(v4) => IpNet::new(IpAddr::V4(v4), v6.prefix_len() - 96).unwrap_or(network),
+            None => network,
+        },
+        _ => network,
+    }
+    .trunc()
+}
+
+/// Virtual host, with its own certificate and broker, selected by the SNI of the client.
+/// Every section of the configuration file is a virtual host, i.e.:
+///
+/// ```ini
+/// [customer1]
+/// server_names = tunnel.customer1.com, *.customer1.org
+/// ssl_certificate = /etc/certs/customer1.pem
+/// uds_server = https://broker.customer1.com/uds/rest/tunnel/ticket
+/// uds_token = customer1_token
+/// ```
+///
+/// Missing values are taken from the global configuration
+#[derive(Debug, Clone, PartialEq)]
+pub struct VirtualHost {
+    pub name: String,
+    pub server_names: Vec<String>,
+    pub ssl_certificate: String,
+    pub ssl_certificate_key: String,
+    pub ssl_password: Password,
+    pub uds_server: String,
+    pub uds_token: String,
+}
+
+impl VirtualHost {
+    /// If `server_name` belongs to this virtual host.
+    /// `*.example.com` matches any direct subdomain of example.com
+    pub fn matches(&self, server_name: &str) -> bool {
+        self.server_names.iter().any(|pattern| {
+            if let Some(domain) = pattern.strip_prefix("*.") {
+                server_name.split_once('.').is_some_and(|(host, rest)| {
+                    !host.is_empty() && rest.eq_ignore_ascii_case(domain)
+                })
+            } else {
+                pattern.eq_ignore_ascii_case(server_name)
+            }
+        })
+    }
+
+    fn load_all(
+        cfg_reader: &config::Config,
+        global: &Config,
+    ) -> Result<Vec<VirtualHost>, config::ConfigError> {
+        let mut virtual_hosts = Vec::new();
+        for (name, value) in config::Source::collect(cfg_reader)? {
+            // Only sections are tables, global keys are plain values
+            let Ok(section) = value.into_table() else {
+                continue;
+            };
+            let get = |key: &str| -> Result<Option<String>, config::ConfigError> {
+                section
+                    .get(key)
+                    .map(|v| v.clone().into_string())
+                    .transpose()
+            };
+
+            let server_names: Vec<String> = get("server_names")?
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim().to_lowercase())
+                .filter(|s| !s.is_empty())
+                .collect();
+            if server_names.is_empty() {
+                return Err(config::ConfigError::Message(format!(
+                    "Virtual host [{}] has no server_names",
+                    name
+                )));
+            }
+
+            let ssl_certificate = get("ssl_certificate")?;
+            // With its own certificate, the key is on it unless configured
+            let ssl_certificate_key = get("ssl_certificate_key")?.unwrap_or_else(|| {
+                if ssl_certificate.is_some() {
+                    String::new()
+                } else {
+                    global.ssl_certificate_key.clone()
+                }
+            });
+            let ssl_password = match get("ssl_password")? {
+                Some(password) => Password::resolve(&password)?,
+                None => global.ssl_password.clone(),
+            };
+
+            virtual_hosts.push(VirtualHost {
+                server_names,
+                ssl_certificate: ssl_certificate.unwrap_or_else(|| global.ssl_certificate.clone()),
+                ssl_certificate_key,
+                ssl_password,
+                uds_server: get("uds_server")?.unwrap_or_else(|| global.uds_server.clone()),
+                uds_token: get("uds_token")?.unwrap_or_else(|| global.uds_token.clone()),
+                name,
+            });
+        }
+        // Sections order is not kept by the reader, so keep it predictable
+        virtual_hosts.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(virtual_hosts)
+    }
+}
+
 pub struct ConfigLoader {
     filename: String,
     uds_server: Option<String>,
@@ -139,15 +400,23 @@ impl ConfigLoader {
             .set_default("logfile", "")?
             .set_default("logsize", "10M")?
             .set_default("lognumber", 4)?
+            .set_default("log_format", "text")?
+            .set_default("audit_file", "")?
             .set_default("address", "0.0.0.0")?
             .set_default("port", 4443)?
             .set_default("ipv6", false)?
-            .set_default("workers", num_cores as u8)?
+            .set_default("proxy_protocol", "off")?
+            .set_default("proxy_trusted", "")?
+            .set_default("workers", 0)?
+            .set_default("runtime_per_core", false)?
             .set_default("ssl_min_tls_version", "1.2")?
             .set_default("ssl_certificate", "/etc/certs/server.pem")?
             .set_default("ssl_certificate_key", "/etc/certs/key.pem")?
-            //.set_default("ssl_password", "")?
+            .set_default("ssl_password", "")?
             .set_default("ssl_ciphers", "")?
+            .set_default("ssl_client_ca", "")?
+            .set_default("ssl_client_crl", "")?
+            .set_default("ssl_ktls", false)?
             .set_default(
                 "uds_server",
                 if let Some(uds_server) = self.uds_server.clone() {
@@ -168,8 +437,25 @@ impl ConfigLoader {
             .set_default("uds_verify_ssl", true)?
             .set_default("command_timeout", 3.0)?
             .set_default("handshake_timeout", 3.0)?
+            .set_default("connect_timeout", 5.0)?
+            .set_default("idle_timeout", 0.0)?
+            .set_default("max_session_duration", 0.0)?
+            .set_default("bandwidth_upload", 0)?
+            .set_default("bandwidth_download", 0)?
+            .set_default("session_bandwidth_upload", 0)?
+            .set_default("session_bandwidth_download", 0)?
             .set_default("secret", "")?
             .set_default("allow", "")?
+            .set_default("rate_limit", 0.0)?
+            .set_default("rate_limit_burst", 20)?
+            .set_default("ban_failures", 0)?
+            .set_default("ban_window", 60.0)?
+            .set_default("ban_time", 600.0)?
+            .set_default("max_connections", 0)?
+            .set_default("max_connections_per_ip", 0)?
+            .set_default("metrics_address", "127.0.0.1")?
+            .set_default("metrics_port", 0)?
+            .set_default("admin_listen", "")?
             .add_source(config::File::new(&self.filename, config::FileFormat::Ini).required(false))
             .add_source(config::Environment::with_prefix("udstunnel"))
             .build()?;
@@ -190,13 +476,22 @@ impl ConfigLoader {
             1024 * 1024,
         );
 
-        // Allow is a comma separated list of IP addresses
-        let allow = cfg_reader.get::<String>("allow").unwrap_or_default();
-        let allow = allow
-            .split(',')
-            .map(|s| s.trim().to_string())
-            .filter(|s| !s.is_empty())
-            .collect();
+        // Allow is a comma separated list of networks or IP addresses
+        let allow = NetworkList::parse("allow", &cfg_reader.get::<String>("allow")?)?;
+
+        let proxy_protocol = cfg_reader
+            .get::<String>("proxy_protocol")?
+            .parse::<ProxyProtocol>()
+            .map_err(|e| config::ConfigError::Message(e.to_string()))?;
+        // Also a list of networks, the load balancers
+        let proxy_trusted =
+            NetworkList::parse("proxy_trusted", &cfg_reader.get::<String>("proxy_trusted")?)?;
+        // Headers from anyone would let any client choose its own address
+        if proxy_protocol != ProxyProtocol::Off && proxy_trusted.is_empty() {
+            return Err(config::ConfigError::Message(
+                "proxy_trusted must list the proxies if proxy_protocol is enabled".to_string(),
+            ));
+        }
 
         let logfile = cfg_reader.get::<String>("logfile")?;
         let logfile = if logfile.is_empty() {
@@ -204,6 +499,24 @@ impl ConfigLoader {
         } else {
             Some(logfile)
         };
+        let log_format = cfg_reader
+            .get::<String>("log_format")?
+            .parse::<LogFormat>()
+            .map_err(|e| config::ConfigError::Message(e.to_string()))?;
+
+        let audit_file = cfg_reader.get::<String>("audit_file")?;
+        let audit_file = if audit_file.is_empty() {
+            None
+        } else {
+            Some(audit_file)
+        };
+
+        // 0 workers means as many as cores
+        let workers = match cfg_reader.get::<u8>("workers")? {
+            0 => num_cores.min(u8::MAX as usize) as u8,
+            workers => workers,
+        };
+
         let command_timeout = cfg_reader
             .get::<f32>("command_timeout")
             .unwrap_or_default()
@@ -217,6 +530,15 @@ impl ConfigLoader {
             .clamp(0.4, 16.0);
         let handshake_timeout = Duration::from_millis((handshake_timeout * 1000.0) as u64);
 
+        let connect_timeout = cfg_reader
+            .get::<f32>("connect_timeout")
+            .unwrap_or_default()
+            .clamp(0.1, 120.0);
+        let connect_timeout = Duration::from_millis((connect_timeout * 1000.0) as u64);
+
+        let idle_timeout = get_seconds(&cfg_reader, "idle_timeout", 0.0)?;
+        let max_session_duration = get_seconds(&cfg_reader, "max_session_duration", 0.0)?;
+
         let uds_timeout = cfg_reader
             .get::<f32>("uds_timeout")
             .unwrap_or_default()
@@ -232,6 +554,11 @@ impl ConfigLoader {
         let result = hasher.finalize();
         let secret = format!("{:x}", result);
 
+        let ban_window = get_seconds(&cfg_reader, "ban_window", 1.0)?;
+        let ban_time = get_seconds(&cfg_reader, "ban_time", 1.0)?;
+
+        let ssl_password = Password::resolve(&cfg_reader.get::<String>("ssl_password")?)?;
+
         // Crate a configuration object
         let cfg = Config {
             pidfile: cfg_reader.get("pidfile")?,
@@ -240,29 +567,70 @@ impl ConfigLoader {
             logfile,
             logsize,
             lognumber: cfg_reader.get("lognumber")?,
+            log_format,
+            audit_file,
             listen_address: cfg_reader.get("address")?,
             listen_port: cfg_reader.get("port")?,
             ipv6: cfg_reader.get("ipv6")?,
-            workers: cfg_reader.get("workers")?,
+            proxy_protocol,
+            proxy_trusted,
+            workers,
+            runtime_per_core: cfg_reader.get("runtime_per_core")?,
             ssl_min_tls_version: cfg_reader.get("ssl_min_tls_version")?,
             ssl_certificate: cfg_reader.get("ssl_certificate")?,
             ssl_certificate_key: cfg_reader.get("ssl_certificate_key")?,
-            //ssl_password: cfg_reader.get("ssl_password")?,
+            ssl_password,
             ssl_ciphers: cfg_reader.get("ssl_ciphers")?,
+            ssl_client_ca: cfg_reader.get("ssl_client_ca")?,
+            ssl_client_crl: cfg_reader.get("ssl_client_crl")?,
+            ssl_ktls: cfg_reader.get("ssl_ktls")?,
             uds_server: cfg_reader.get("uds_server")?,
             uds_token: cfg_reader.get("uds_token")?,
             uds_timeout,
             uds_verify_ssl: cfg_reader.get("uds_verify_ssl")?,
             command_timeout,
             handshake_timeout,
+            connect_timeout,
+            idle_timeout,
+            max_session_duration,
+            bandwidth_upload: cfg_reader.get("bandwidth_upload")?,
+            bandwidth_download: cfg_reader.get("bandwidth_download")?,
+            session_bandwidth_upload: cfg_reader.get("session_bandwidth_upload")?,
+            session_bandwidth_download: cfg_reader.get("session_bandwidth_download")?,
             secret,
             allow,
+            rate_limit: cfg_reader.get::<f64>("rate_limit")?.max(0.0),
+            rate_limit_burst: cfg_reader.get("rate_limit_burst")?,
+            ban_failures: cfg_reader.get("ban_failures")?,
+            ban_window,
+            ban_time,
+            max_connections: cfg_reader.get("max_connections")?,
+            max_connections_per_ip: cfg_reader.get("max_connections_per_ip")?,
+            metrics_address: cfg_reader.get("metrics_address")?,
+            metrics_port: cfg_reader.get("metrics_port")?,
+            admin_listen: cfg_reader.get("admin_listen")?,
+            virtual_hosts: Vec::new(),
+        };
+        let cfg = Config {
+            virtual_hosts: VirtualHost::load_all(&cfg_reader, &cfg)?,
+            ..cfg
         };
         log::debug!("Configuration loaded: {:?}", cfg);
         Ok(cfg)
     }
 }
 
+// Setting in seconds (fractions allowed), at least `min`
+fn get_seconds(
+    cfg_reader: &config::Config,
+    key: &str,
+    min: f64,
+) -> Result<Duration, config::ConfigError> {
+    let seconds = cfg_reader.get::<f64>(key)?.max(min);
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| config::ConfigError::Message(format!("Invalid {}: {} seconds", key, seconds)))
+}
+
 impl Default for ConfigLoader {
     fn default() -> Self {
         Self::new()
@@ -0,0 +1,199 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{net::TcpStream, task::JoinSet, time::timeout};
+
+use super::consts;
+
+/// Connects to `host:port` in `connect_timeout` at most, trying all the addresses it resolves
+/// to as Happy Eyeballs (RFC 8305) does. If `ipv6_only`, names are resolved to IPv6 addresses
+/// only (IP literals are always used as they are)
+pub async fn connect(
+    host: &str,
+    port: u16,
+    ipv6_only: bool,
+    connect_timeout: Duration,
+) -> io::Result<TcpStream> {
+    let attempt_delay = Duration::from_millis(consts::CONNECTION_ATTEMPT_DELAY_MS);
+    timeout(connect_timeout, async {
+        let addrs = resolve(host, port, ipv6_only).await?;
+        happy_eyeballs(addrs, attempt_delay).await
+    })
+    .await
+    .unwrap_or_else(|_| {
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("Timeout connecting to {}:{}", host, port),
+        ))
+    })
+}
+
+async fn resolve(host: &str, port: u16, ipv6_only: bool) -> io::Result<VecDeque<SocketAddr>> {
+    // IPv6 literals can come with brackets
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(VecDeque::from([SocketAddr::new(ip, port)]));
+    }
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await?
+        .filter(|addr| !ipv6_only || addr.is_ipv6())
+        .collect();
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "No {}addresses found for {}",
+                if ipv6_only { "IPv6 " } else { "" },
+                host
+            ),
+        ));
+    }
+    Ok(interleave(addrs))
+}
+
+// Alternates the address families, starting with IPv6 (RFC 8305, section 4).
+// The resolver order is kept inside each family
+fn interleave(addrs: Vec<SocketAddr>) -> VecDeque<SocketAddr> {
+    let (ipv6, ipv4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut ipv6 = ipv6.into_iter();
+    let mut ipv4 = ipv4.into_iter();
+    let mut sorted = VecDeque::new();
+    loop {
+        match (ipv6.next(), ipv4.next()) {
+            (None, None) => return sorted,
+            (v6, v4) => sorted.extend(v6.into_iter().chain(v4)),
+        }
+    }
+}
+
+// Starts a connection attempt to every address in order, each one `attempt_delay` after the
+// previous one (or as soon as it fails). The first connection established wins, the attempts
+// still running are cancelled
+async fn happy_eyeballs(
+    mut addrs: VecDeque<SocketAddr>,
+    attempt_delay: Duration,
+) -> io::Result<TcpStream> {
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+    loop {
+        if attempts.is_empty() {
+            let Some(addr) = addrs.pop_front() else {
+                return Err(last_error.unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "No addresses to connect to")
+                }));
+            };
+            attempts.spawn(TcpStream::connect(addr));
+        }
+        tokio::select! {
+            Some(result) = attempts.join_next() => {
+                match result {
+                    Ok(Ok(stream)) => return Ok(stream),
+                    Ok(Err(e)) => {
+                        log::debug!("Connection attempt failed: {:?}", e);
+                        last_error = Some(e);
+                    }
+                    Err(e) => last_error = Some(io::Error::other(e)),
+                }
+                // Failed, next one right now
+                if let Some(addr) = addrs.pop_front() {
+                    attempts.spawn(TcpStream::connect(addr));
+                }
+            }
+            _ = tokio::time::sleep(attempt_delay), if !addrs.is_empty() => {
+                if let Some(addr) = addrs.pop_front() {
+                    attempts.spawn(TcpStream::connect(addr));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    // Port with nothing listening on it
+    async fn closed_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn test_interleave() {
+        let sorted = interleave(vec![
+            addr("10.0.0.1:80"),
+            addr("10.0.0.2:80"),
+            addr("10.0.0.3:80"),
+            addr("[2001:db8::1]:80"),
+            addr("[2001:db8::2]:80"),
+        ]);
+        assert_eq!(
+            sorted,
+            [
+                addr("[2001:db8::1]:80"),
+                addr("10.0.0.1:80"),
+                addr("[2001:db8::2]:80"),
+                addr("10.0.0.2:80"),
+                addr("10.0.0.3:80"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        // Literals are used as they are, even if IPv6 only
+        let addrs = resolve("127.0.0.1", 80, true).await.unwrap();
+        assert_eq!(addrs, [addr("127.0.0.1:80")]);
+        let addrs = resolve("[::1]", 80, false).await.unwrap();
+        assert_eq!(addrs, [addr("[::1]:80")]);
+        // Names are filtered (localhost may have no IPv6 address at all)
+        match resolve("localhost", 80, true).await {
+            Ok(addrs) => assert!(addrs.iter().all(SocketAddr::is_ipv6)),
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs_fallback() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let good = listener.local_addr().unwrap();
+        let bad = SocketAddr::new(good.ip(), closed_port().await);
+
+        // Failed attempts go on with the next address, without waiting for the delay
+        let start = std::time::Instant::now();
+        let stream = happy_eyeballs(VecDeque::from([bad, bad, good]), Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(stream.peer_addr().unwrap(), good);
+
+        // Error of the last attempt if none connects
+        let err = happy_eyeballs(VecDeque::from([bad, bad]), Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        // TEST-NET-1 address, never answers (or is not routable at all)
+        let start = std::time::Instant::now();
+        let result = connect("192.0.2.1", 80, false, Duration::from_millis(300)).await;
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
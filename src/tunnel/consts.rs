@@ -1,4 +1,7 @@
 pub const BUFFER_SIZE: usize = 1024 * 16;
+// Relay buffers, adapted to the traffic between these sizes (both must be powers of two)
+pub const MIN_BUFFER_SIZE: usize = 1024 * 4;
+pub const MAX_BUFFER_SIZE: usize = 1024 * 64;
 pub const HANDSHAKE_V1: &[u8] = b"\x5AMGB\xA5\x01\x00";
 pub const TICKET_LENGTH: usize = 48;
 pub const SECRET_LENGTH: usize = 64;
@@ -17,6 +20,13 @@ pub const RESPONSE_ERROR_TIMEOUT: &str = "TIMEOUT";
 pub const RESPONSE_ERROR_HANDSHAKE: &str = "ERROR_HANDSHAKE";
 pub const RESPONSE_FORBIDDEN: &str = "FORBIDDEN";
 pub const RESPONSE_ERROR_CONNECT: &str = "ERROR_CONNECT";
+pub const RESPONSE_ERROR_BUSY: &str = "ERROR_BUSY";
 pub const RESPONSE_OK: &str = "OK";
 
+// Happy Eyeballs (RFC 8305), delay before trying the next address of a server while connecting
+pub const CONNECTION_ATTEMPT_DELAY_MS: u64 = 250;
+
+// Interval to check if the certificate files have changed
+pub const CERT_CHECK_INTERVAL_SECS: u64 = 30;
+
 pub const CONFIGFILE: &str = "/etc/udstunnel.conf";
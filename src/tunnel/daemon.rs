@@ -0,0 +1,148 @@
+// Daemon related helpers: pid file and privileges drop
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// Pid file, removed (or emptied, if not allowed anymore) when dropped
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes the pid file. If privileges are going to be dropped to `user`, it's owned by it
+    pub fn create(path: &str, user: &str) -> Result<Self> {
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Error writing pid file {}", path))?;
+        give_to_user(Path::new(path), user)?;
+        Ok(PidFile {
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // After dropping privileges, removing it is not allowed on a directory owned by root
+        // (i.e. /run), but as it's ours, it can be emptied, so it does not point to a dead process
+        if std::fs::remove_file(&self.path).is_err() {
+            if let Err(e) = std::fs::write(&self.path, "") {
+                log::warn!("Error removing pid file {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// Gives the file to `user`, if privileges are going to be dropped to it (`user` is set and the
+/// process runs as root), so it can still be written once dropped
+#[cfg(unix)]
+pub fn give_to_user(path: &Path, user: &str) -> Result<()> {
+    if !user.is_empty() && is_root() {
+        let (uid, gid) = lookup_user(user)?;
+        std::os::unix::fs::chown(path, Some(uid), Some(gid))
+            .with_context(|| format!("Error changing owner of {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn give_to_user(_path: &Path, _user: &str) -> Result<()> {
+    Ok(())
+}
+
+/// Switches the process to `user` (and its primary group).
+/// Does nothing if `user` is empty or the process is not running as root
+#[cfg(unix)]
+pub fn drop_privileges(user: &str) -> Result<()> {
+    use std::io;
+
+    if user.is_empty() {
+        return Ok(());
+    }
+    if !is_root() {
+        log::info!("Not running as root, keeping current user");
+        return Ok(());
+    }
+
+    let (uid, gid) = lookup_user(user)?;
+
+    // Group first, once uid is changed we are not allowed to change it anymore
+    // Safety: plain syscalls, gid is a valid pointer to one gid_t
+    unsafe {
+        if libc::setgroups(1, &gid) != 0 {
+            return Err(io::Error::last_os_error()).context("Error setting groups");
+        }
+        if libc::setgid(gid) != 0 {
+            return Err(io::Error::last_os_error()).context("Error setting gid");
+        }
+        if libc::setuid(uid) != 0 {
+            return Err(io::Error::last_os_error()).context("Error setting uid");
+        }
+    }
+    log::info!("Running as user {} (uid {}, gid {})", user, uid, gid);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(_user: &str) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    // Safety: geteuid has no preconditions
+    unsafe { libc::geteuid() == 0 }
+}
+
+// Uid and primary gid of `user`
+#[cfg(unix)]
+fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let c_user = std::ffi::CString::new(user).context("Invalid user name")?;
+    // Safety: c_user is a valid C string. The returned struct is only read here,
+    // before any other call that could overwrite it
+    unsafe {
+        let pw = libc::getpwnam(c_user.as_ptr());
+        if pw.is_null() {
+            anyhow::bail!("User {} not found", user);
+        }
+        Ok(((*pw).pw_uid, (*pw).pw_gid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pidfile_is_removed_on_drop() {
+        let path = std::env::temp_dir().join(format!("udstunnel-{}.pid", uuid::Uuid::new_v4()));
+        let pidfile = PidFile::create(&path.to_string_lossy(), "").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap().trim(),
+            std::process::id().to_string()
+        );
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pidfile_is_owned_by_user() {
+        use std::os::unix::fs::MetadataExt;
+
+        let path = std::env::temp_dir().join(format!("udstunnel-{}.pid", uuid::Uuid::new_v4()));
+        let pidfile = PidFile::create(&path.to_string_lossy(), "nobody").unwrap();
+        // Only root can give it away
+        if is_root() {
+            let (uid, _) = lookup_user("nobody").unwrap();
+            assert_eq!(std::fs::metadata(&path).unwrap().uid(), uid);
+        }
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_drop_privileges_no_user() {
+        assert!(drop_privileges("").is_ok());
+    }
+}
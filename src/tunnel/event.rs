@@ -7,6 +7,7 @@ use std::task::{Context, Poll, Waker};
 
 static EVENT_ID: AtomicU64 = AtomicU64::new(0);
 
+#[derive(Debug)]
 pub struct Event {
     state: Arc<Mutex<State>>,
     waker_id: u64, // Do not need sync, as it will be only written once at creation/clone
@@ -0,0 +1,55 @@
+// Minimal http/1.1 helpers for the internal plain http endpoints (metrics, admin)
+// Only one request per connection is served, and the connection is closed after the response
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::timeout,
+};
+
+pub(crate) struct Request {
+    pub method: String,
+    pub path: String,
+}
+
+/// Reads the request line of an http request, with timeout
+pub(crate) async fn read_request<S>(stream: &mut S, read_timeout: Duration) -> Result<Request>
+where
+    S: AsyncRead + Unpin,
+{
+    // Only the request line is relevant, 1024 bytes are more than enough
+    let mut buf = [0u8; 1024];
+    let size = timeout(read_timeout, stream.read(&mut buf)).await??;
+    let request = String::from_utf8_lossy(&buf[..size]);
+    let mut request_line = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+    Ok(Request {
+        method: request_line.next().unwrap_or_default().to_string(),
+        path: request_line.next().unwrap_or_default().to_string(),
+    })
+}
+
+pub(crate) async fn write_response<S>(
+    stream: &mut S,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
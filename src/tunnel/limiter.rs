@@ -0,0 +1,406 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::config;
+
+// Once this many sources are tracked, idle ones are forgotten
+const MAX_TRACKED_SOURCES: usize = 10000;
+// Forgetting them is a full scan, so not done more often than this
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Per source IP protection against abuse of the tunnel (and so, of the broker):
+///   * A token bucket, consumed on every connection and every OPEN command
+///   * Temporary bans of sources with too many invalid tickets on a time window
+///   * Maximum concurrent connections, globally and per source
+///
+/// Shared by all the tunnel servers, so limits are global to the process
+#[derive(Debug)]
+pub struct Limiter {
+    rate: f64, // Tokens per second, 0 means no rate limit
+    burst: f64,
+    ban_failures: usize, // 0 means never ban
+    ban_window: Duration,
+    ban_time: Duration,
+    sources: Mutex<Sources>,
+    // Connection slots, None means no limit
+    connections: Option<Arc<Semaphore>>,
+    max_connections_per_source: usize, // 0 means no limit
+    open_connections: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// A connection of a source, released on drop
+#[derive(Debug)]
+pub struct SourceSlot {
+    limiter: Arc<Limiter>,
+    ip: IpAddr,
+}
+
+impl Drop for SourceSlot {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open_connections.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Sources {
+    map: HashMap<IpAddr, Source>,
+    next_prune: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Source {
+    tokens: f64,
+    updated: Instant,
+    failures: VecDeque<Instant>, // Ticket failures inside the ban window
+    banned_until: Option<Instant>,
+}
+
+impl Source {
+    fn new(burst: f64, now: Instant) -> Self {
+        Source {
+            tokens: burst,
+            updated: now,
+            failures: VecDeque::new(),
+            banned_until: None,
+        }
+    }
+
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+
+    // Nothing to remember: bucket is full, and no failures or bans
+    fn is_idle(&self, limiter: &Limiter, now: Instant) -> bool {
+        !self.is_banned(now)
+            && self
+                .failures
+                .back()
+                .is_none_or(|last| now.duration_since(*last) > limiter.ban_window)
+            && self.tokens + now.duration_since(self.updated).as_secs_f64() * limiter.rate
+                >= limiter.burst
+    }
+}
+
+impl Limiter {
+    pub fn new(config: &config::Config) -> Self {
+        Limiter {
+            rate: config.rate_limit,
+            burst: config.rate_limit_burst.max(1) as f64,
+            ban_failures: config.ban_failures as usize,
+            ban_window: config.ban_window,
+            ban_time: config.ban_time,
+            sources: Mutex::new(Sources::default()),
+            connections: (config.max_connections > 0)
+                .then(|| Arc::new(Semaphore::new(config.max_connections))),
+            max_connections_per_source: config.max_connections_per_ip,
+            open_connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// If all the global connection slots are in use
+    pub fn is_full(&self) -> bool {
+        self.connections
+            .as_ref()
+            .is_some_and(|connections| connections.available_permits() == 0)
+    }
+
+    /// Waits for a global connection slot, released on drop. None if there is no limit
+    pub async fn connection_slot(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.connections {
+            // Semaphore is never closed
+            Some(connections) => connections.clone().acquire_owned().await.ok(),
+            None => None,
+        }
+    }
+
+    /// Takes a connection slot of the source. None if it has already too many connections
+    pub fn source_slot(self: &Arc<Self>, ip: IpAddr) -> Option<SourceSlot> {
+        let ip = ip.to_canonical();
+        let mut open = self.open_connections.lock().unwrap();
+        let count = open.entry(ip).or_default();
+        if self.max_connections_per_source > 0 && *count >= self.max_connections_per_source {
+            if *count == 0 {
+                open.remove(&ip);
+            }
+            return None;
+        }
+        *count += 1;
+        Some(SourceSlot {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    /// If the source is currently banned
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.is_banned_at(ip.to_canonical(), Instant::now())
+    }
+
+    /// Consumes a token of the source. Returns false if it has none left
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        self.allow_at(ip.to_canonical(), Instant::now())
+    }
+
+    /// Records an invalid ticket from the source. Returns true if the source gets banned by it
+    pub fn add_ticket_failure(&self, ip: IpAddr) -> bool {
+        self.add_ticket_failure_at(ip.to_canonical(), Instant::now())
+    }
+
+    fn is_banned_at(&self, ip: IpAddr, now: Instant) -> bool {
+        self.sources
+            .lock()
+            .unwrap()
+            .map
+            .get(&ip)
+            .is_some_and(|source| source.is_banned(now))
+    }
+
+    fn allow_at(&self, ip: IpAddr, now: Instant) -> bool {
+        if self.rate <= 0.0 {
+            return true;
+        }
+        let mut sources = self.sources.lock().unwrap();
+        let Some(source) = self.entry(&mut sources, ip, now) else {
+            // Untracked, so as a new one, with a full bucket
+            return true;
+        };
+
+        let elapsed = now.duration_since(source.updated).as_secs_f64();
+        source.tokens = (source.tokens + elapsed * self.rate).min(self.burst);
+        source.updated = now;
+        if source.tokens < 1.0 {
+            return false;
+        }
+        source.tokens -= 1.0;
+        true
+    }
+
+    fn add_ticket_failure_at(&self, ip: IpAddr, now: Instant) -> bool {
+        if self.ban_failures == 0 {
+            return false;
+        }
+        let mut sources = self.sources.lock().unwrap();
+        let Some(source) = self.entry(&mut sources, ip, now) else {
+            return false;
+        };
+
+        source.failures.push_back(now);
+        while source
+            .failures
+            .front()
+            .is_some_and(|first| now.duration_since(*first) > self.ban_window)
+        {
+            source.failures.pop_front();
+        }
+        if source.failures.len() < self.ban_failures {
+            return false;
+        }
+        source.failures.clear();
+        source.banned_until = Some(now + self.ban_time);
+        true
+    }
+
+    // Tracked source, added if new. Once full, idle sources are forgotten. The others (i.e. banned
+    // ones) are never, so if none is idle, the new source is not tracked (None)
+    fn entry<'a>(
+        &self,
+        sources: &'a mut Sources,
+        ip: IpAddr,
+        now: Instant,
+    ) -> Option<&'a mut Source> {
+        if sources.map.len() >= MAX_TRACKED_SOURCES && !sources.map.contains_key(&ip) {
+            if sources.next_prune.is_none_or(|next| now >= next) {
+                sources.map.retain(|_, source| !source.is_idle(self, now));
+                sources.next_prune = Some(now + PRUNE_INTERVAL);
+            }
+            if sources.map.len() >= MAX_TRACKED_SOURCES {
+                return None;
+            }
+        }
+        Some(
+            sources
+                .map
+                .entry(ip)
+                .or_insert_with(|| Source::new(self.burst, now)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(rate: f64, burst: u32, ban_failures: u32) -> Limiter {
+        Limiter {
+            rate,
+            burst: burst as f64,
+            ban_failures: ban_failures as usize,
+            ban_window: Duration::from_secs(60),
+            ban_time: Duration::from_secs(600),
+            sources: Mutex::new(Sources::default()),
+            connections: None,
+            max_connections_per_source: 0,
+            open_connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_slots() {
+        let limited = Limiter {
+            connections: Some(Arc::new(Semaphore::new(2))),
+            ..limiter(0.0, 1, 0)
+        };
+        let first = limited.connection_slot().await;
+        let _second = limited.connection_slot().await;
+        assert!(limited.is_full());
+        let third = tokio::time::timeout(Duration::from_millis(50), limited.connection_slot());
+        assert!(third.await.is_err());
+        drop(first);
+        assert!(!limited.is_full());
+        assert!(limited.connection_slot().await.is_some());
+
+        let unlimited = limiter(0.0, 1, 0);
+        assert!(unlimited.connection_slot().await.is_none());
+        assert!(!unlimited.is_full());
+    }
+
+    #[test]
+    fn test_source_slots() {
+        let limiter = Arc::new(Limiter {
+            max_connections_per_source: 2,
+            ..limiter(0.0, 1, 0)
+        });
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let first = limiter.source_slot(ip).unwrap();
+        let second = limiter
+            .source_slot("::ffff:192.0.2.1".parse().unwrap())
+            .unwrap();
+        assert!(limiter.source_slot(ip).is_none());
+        // Other sources are not affected
+        assert!(limiter.source_slot("192.0.2.2".parse().unwrap()).is_some());
+        drop(first);
+        assert!(limiter.source_slot(ip).is_some());
+
+        drop(second);
+        assert!(limiter.open_connections.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = limiter(2.0, 3, 0);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.allow_at(ip, now));
+        }
+        assert!(!limiter.allow_at(ip, now));
+        // Other sources have their own bucket
+        assert!(limiter.allow_at(other, now));
+        // 2 tokens per second
+        assert!(limiter.allow_at(ip, now + Duration::from_millis(500)));
+        assert!(!limiter.allow_at(ip, now + Duration::from_millis(500)));
+        // Never more than burst
+        let later = now + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.allow_at(ip, later));
+        }
+        assert!(!limiter.allow_at(ip, later));
+    }
+
+    #[test]
+    fn test_no_rate_limit() {
+        let limiter = limiter(0.0, 1, 0);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..100 {
+            assert!(limiter.allow(ip));
+        }
+        assert!(limiter.sources.lock().unwrap().map.is_empty());
+    }
+
+    #[test]
+    fn test_ban_after_failures() {
+        let limiter = limiter(0.0, 1, 3);
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(!limiter.add_ticket_failure_at(ip, now));
+        // Out of the window, forgotten
+        let now = now + Duration::from_secs(61);
+        assert!(!limiter.add_ticket_failure_at(ip, now));
+        assert!(!limiter.add_ticket_failure_at(ip, now));
+        assert!(!limiter.is_banned_at(ip, now));
+        assert!(limiter.add_ticket_failure_at(ip, now));
+        assert!(limiter.is_banned_at(ip, now));
+        assert!(limiter.is_banned_at(ip, now + Duration::from_secs(599)));
+        assert!(!limiter.is_banned_at(ip, now + Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn test_mapped_addresses_are_the_same_source() {
+        let limiter = limiter(0.0, 1, 1);
+        assert!(limiter.add_ticket_failure("::ffff:192.0.2.1".parse().unwrap()));
+        assert!(limiter.is_banned("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_idle_sources_are_forgotten() {
+        let limiter = limiter(1.0, 1, 1);
+        let now = Instant::now();
+        let banned: IpAddr = "2001:db8::1".parse().unwrap();
+        limiter.add_ticket_failure_at(banned, now);
+        for i in 1..MAX_TRACKED_SOURCES as u32 {
+            assert!(limiter.allow_at(IpAddr::from(i.to_be_bytes()), now));
+        }
+
+        // Once refilled, only the banned one is kept
+        let later = now + Duration::from_secs(2);
+        assert!(limiter.allow_at("2001:db8::2".parse().unwrap(), later));
+        assert_eq!(limiter.sources.lock().unwrap().map.len(), 2);
+        assert!(limiter.is_banned_at(banned, later));
+    }
+
+    #[test]
+    fn test_tracked_sources_are_bounded() {
+        // No rate limit, so only the ticket failures add sources
+        let limiter = limiter(0.0, 1, 3);
+        let now = Instant::now();
+        let banned: IpAddr = "2001:db8::1".parse().unwrap();
+        for _ in 0..3 {
+            limiter.add_ticket_failure_at(banned, now);
+        }
+        for i in 0..MAX_TRACKED_SOURCES as u32 + 100 {
+            assert!(!limiter.add_ticket_failure_at(IpAddr::from(i.to_be_bytes()), now));
+        }
+        assert_eq!(
+            limiter.sources.lock().unwrap().map.len(),
+            MAX_TRACKED_SOURCES
+        );
+        // Flooding with new sources never forgets bans or failures, the new ones are not tracked
+        assert!(limiter.is_banned_at(banned, now));
+        let first = IpAddr::from(0u32.to_be_bytes());
+        assert!(!limiter.add_ticket_failure_at(first, now));
+        assert!(limiter.add_ticket_failure_at(first, now));
+        let last = IpAddr::from((MAX_TRACKED_SOURCES as u32 + 99).to_be_bytes());
+        assert!(!limiter.sources.lock().unwrap().map.contains_key(&last));
+
+        // Until the failures are out of the window
+        let later = now + Duration::from_secs(61);
+        assert!(!limiter.add_ticket_failure_at(last, later));
+        assert!(limiter.sources.lock().unwrap().map.contains_key(&last));
+        assert!(limiter.is_banned_at(banned, later));
+    }
+}
@@ -1,19 +1,229 @@
-use std::io::Write;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use env_logger;
+use serde_json::{Map, Value};
+
+/// Format of the log records
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json, // One json object per line, including the structured fields of the record
+}
+
+impl FromStr for LogFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" | "" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("Invalid log format, valid are text and json"),
+        }
+    }
+}
+
+// Incremented by `reopen` (i.e. on SIGHUP), checked by the log file writer before each write
+static REOPEN_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Requests the log file to be reopened on next write.
+/// Used for external rotation (logrotate), that moves the file away and signals us
+pub fn reopen() {
+    REOPEN_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Log file `path` and its `backups` (the ones that exist)
+pub fn files(path: &str, backups: u32) -> Vec<PathBuf> {
+    let path = PathBuf::from(path);
+    (1..=backups)
+        .map(|index| backup_path(&path, index))
+        .chain([path.clone()])
+        .filter(|path| path.exists())
+        .collect()
+}
+
+fn backup_path(path: &Path, index: u32) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{}", index));
+    PathBuf::from(path)
+}
+
+/// Log file that rotates itself once it reaches `max_size` bytes,
+/// keeping `backups` old files (`logfile.1` is the newest, `logfile.<backups>` the oldest)
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    backups: u32,
+    file: Option<File>,
+    size: u64,
+    generation: u64,
+    rotation_failed: bool, // Not retried until reopened, the file just grows meanwhile
+}
+
+impl RotatingFile {
+    pub fn new(path: &str, max_size: u64, backups: u32) -> io::Result<Self> {
+        let mut rotating_file = RotatingFile {
+            path: PathBuf::from(path),
+            max_size,
+            backups,
+            file: None,
+            size: 0,
+            generation: 0,
+            rotation_failed: false,
+        };
+        rotating_file.open()?;
+        Ok(rotating_file)
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.file = Some(file);
+        self.generation = REOPEN_GENERATION.load(Ordering::Relaxed);
+        self.rotation_failed = false;
+        Ok(())
+    }
+
+    fn backup_path(&self, index: u32) -> PathBuf {
+        backup_path(&self.path, index)
+    }
+
+    // The current file is kept open until the new one is, so if anything fails, records are
+    // still appended to it (the error is reported only once, there may be no other log)
+    fn rotate(&mut self) {
+        if let Err(e) = self.move_away().and_then(|_| self.open()) {
+            eprintln!(
+                "Error rotating log file {}: {}, appending to it",
+                self.path.display(),
+                e
+            );
+            self.rotation_failed = true;
+        }
+    }
+
+    fn move_away(&self) -> io::Result<()> {
+        if self.backups == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.backups).rev() {
+                let from = self.backup_path(index);
+                if from.exists() {
+                    std::fs::rename(&from, self.backup_path(index + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.backup_path(1))?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let generation = REOPEN_GENERATION.load(Ordering::Relaxed);
+        if self.generation != generation || self.file.is_none() {
+            if let Err(e) = self.open() {
+                // As on rotation, the current file (if any) is kept, until reopened again
+                self.generation = generation;
+                if self.file.is_none() {
+                    return Err(e);
+                }
+                eprintln!(
+                    "Error reopening log file {}: {}, appending to the current one",
+                    self.path.display(),
+                    e
+                );
+            }
+        }
+        if !self.rotation_failed && self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate();
+        }
+        let written = match self.file.as_mut() {
+            Some(file) => file.write(buf)?,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "Log file not open")),
+        };
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+// Collects the structured fields (key = value) of a log record into a json object
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for JsonVisitor<'_> {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        let value = if let Some(v) = value.to_u64() {
+            Value::from(v)
+        } else if let Some(v) = value.to_i64() {
+            Value::from(v)
+        } else if let Some(v) = value.to_f64() {
+            Value::from(v)
+        } else if let Some(v) = value.to_bool() {
+            Value::from(v)
+        } else {
+            Value::from(value.to_string())
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+fn json_record(timestamp: String, record: &log::Record) -> String {
+    let mut fields = Map::new();
+    fields.insert("timestamp".into(), timestamp.into());
+    fields.insert("level".into(), record.level().as_str().into());
+    fields.insert("target".into(), record.target().into());
+    fields.insert("message".into(), record.args().to_string().into());
+    record
+        .key_values()
+        .visit(&mut JsonVisitor(&mut fields))
+        .unwrap_or_default();
+    Value::Object(fields).to_string()
+}
 
 // Setup logging
-pub fn setup(filename: &Option<String>, level: &str) {
+// If a log file is provided, it's rotated on `size` bytes, keeping `number` backups
+pub fn setup(filename: &Option<String>, level: &str, size: u32, number: u32, format: LogFormat) {
     // If already initialized, return
     let mut target = env_logger::Target::Stderr;
     if let Some(logfile) = filename {
-        target = env_logger::Target::Pipe(Box::new(
-            std::fs::OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(logfile)
-                .unwrap(),
-        ));
+        match RotatingFile::new(logfile, size as u64, number) {
+            Ok(file) => target = env_logger::Target::Pipe(Box::new(file)),
+            Err(e) => eprintln!("Error opening log file {}: {}, using stderr", logfile, e),
+        }
+    }
+
+    if format == LogFormat::Json {
+        env_logger::Builder::from_env(
+            env_logger::Env::default().default_filter_or(level.to_string()),
+        )
+        .target(target)
+        .format(|buf, record| {
+            let timestamp = buf.timestamp_millis().to_string();
+            writeln!(buf, "{}", json_record(timestamp, record))
+        })
+        .try_init()
+        .unwrap_or_default();
+        return;
     }
 
     #[cfg(not(debug_assertions))]
@@ -45,3 +255,163 @@ pub fn setup(filename: &Option<String>, level: &str) {
     //     .format_module_path(true);
     // #[cfg(not(debug_assertions))]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_log(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("udstunnel-{}-{}.log", name, uuid::Uuid::new_v4()));
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_json_record() {
+        let tunnel_id = "0123456789abc".to_string();
+        let kvs: [(&str, log::kv::Value); 3] = [
+            ("tunnel_id", log::kv::Value::from(tunnel_id.as_str())),
+            ("event", log::kv::Value::from("open")),
+            ("sent", log::kv::Value::from(1234u64)),
+        ];
+        let record = log::Record::builder()
+            .args(format_args!("OPEN TUNNEL"))
+            .level(log::Level::Info)
+            .target("udstunnel")
+            .key_values(&kvs)
+            .build();
+        let json: Value =
+            serde_json::from_str(&json_record("2024-01-01T00:00:00.000Z".into(), &record)).unwrap();
+        assert_eq!(json["level"], "INFO");
+        assert_eq!(json["message"], "OPEN TUNNEL");
+        assert_eq!(json["tunnel_id"], "0123456789abc");
+        assert_eq!(json["event"], "open");
+        assert_eq!(json["sent"], 1234);
+    }
+
+    #[test]
+    fn test_log_format_from_str() {
+        assert_eq!("JSON".parse::<LogFormat>(), Ok(LogFormat::Json));
+        assert_eq!("text".parse::<LogFormat>(), Ok(LogFormat::Text));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn test_rotating_file_keeps_backups() {
+        let path = temp_log("rotate");
+        let mut file = RotatingFile::new(&path, 10, 2).unwrap();
+        for line in [
+            "first-line\n",
+            "second-line\n",
+            "third-line\n",
+            "fourth-line\n",
+        ] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "fourth-line\n");
+        assert_eq!(
+            std::fs::read_to_string(format!("{}.1", path)).unwrap(),
+            "third-line\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(format!("{}.2", path)).unwrap(),
+            "second-line\n"
+        );
+        // Only 2 backups are kept
+        assert!(!std::path::Path::new(&format!("{}.3", path)).exists());
+
+        for p in [path.clone(), format!("{}.1", path), format!("{}.2", path)] {
+            std::fs::remove_file(p).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_log_files() {
+        let path = temp_log("files");
+        assert!(files(&path, 3).is_empty());
+        for existing in [path.clone(), format!("{}.1", path), format!("{}.3", path)] {
+            std::fs::write(existing, "").unwrap();
+        }
+        assert_eq!(
+            files(&path, 2),
+            [PathBuf::from(format!("{}.1", path)), PathBuf::from(&path)]
+        );
+        for existing in files(&path, 3) {
+            std::fs::remove_file(existing).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_rotating_file_reopen() {
+        let path = temp_log("reopen");
+        let mut file = RotatingFile::new(&path, 1024, 1).unwrap();
+        file.write_all(b"before\n").unwrap();
+
+        // External rotation, moves the file away
+        let moved = format!("{}.moved", path);
+        std::fs::rename(&path, &moved).unwrap();
+        reopen();
+        file.write_all(b"after\n").unwrap();
+
+        assert_eq!(std::fs::read_to_string(&moved).unwrap(), "before\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "after\n");
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(moved).unwrap();
+    }
+
+    #[test]
+    fn test_rotating_file_reopen_fails() {
+        let path = temp_log("reopen-fails");
+        let mut file = RotatingFile::new(&path, 1024, 1).unwrap();
+        file.write_all(b"before\n").unwrap();
+
+        // Moved away, and a file can't be opened where it was
+        let moved = format!("{}.moved", path);
+        std::fs::rename(&path, &moved).unwrap();
+        std::fs::create_dir(&path).unwrap();
+        reopen();
+        file.write_all(b"after\n").unwrap();
+        file.write_all(b"once more\n").unwrap();
+        file.flush().unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&moved).unwrap(),
+            "before\nafter\nonce more\n"
+        );
+
+        std::fs::remove_dir(path).unwrap();
+        std::fs::remove_file(moved).unwrap();
+    }
+
+    #[test]
+    fn test_rotating_file_rotation_fails() {
+        let path = temp_log("rotate-fails");
+        // A file can't be renamed over a directory
+        let backup = format!("{}.1", path);
+        std::fs::create_dir(&backup).unwrap();
+        let mut file = RotatingFile::new(&path, 10, 1).unwrap();
+        for line in ["first-line\n", "second-line\n", "third-line\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "first-line\nsecond-line\nthird-line\n"
+        );
+
+        // Retried once reopened
+        std::fs::remove_dir(&backup).unwrap();
+        reopen();
+        file.write_all(b"fourth-line\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "fourth-line\n");
+        assert_eq!(
+            std::fs::read_to_string(&backup).unwrap(),
+            "first-line\nsecond-line\nthird-line\n"
+        );
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(backup).unwrap();
+    }
+}
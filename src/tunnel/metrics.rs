@@ -0,0 +1,230 @@
+use std::{fmt::Write as _, sync::Arc};
+
+use anyhow::{Context, Result};
+use tokio::net::{TcpListener, TcpStream};
+
+use super::{config, event, http, stats};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Plain http listener that serves the tunnel stats in prometheus text format on `/metrics`
+pub struct MetricsServer {
+    pub config: config::Config,
+    pub stats: Arc<stats::Stats>,
+    // Bound listener, set by `listen`
+    listener: Option<TcpListener>,
+}
+
+impl MetricsServer {
+    pub fn new(config: &config::Config, stats: Arc<stats::Stats>) -> Self {
+        MetricsServer {
+            config: config.clone(),
+            stats,
+            listener: None,
+        }
+    }
+
+    /// Binds the listener, so it can be done before dropping privileges.
+    /// If not called, `run` does it
+    pub async fn listen(&mut self) -> Result<()> {
+        if self.listener.is_some() {
+            return Ok(());
+        }
+        let address = if self.config.metrics_address.contains(':')
+            && !self.config.metrics_address.starts_with('[')
+        {
+            format!(
+                "[{}]:{}",
+                self.config.metrics_address, self.config.metrics_port
+            )
+        } else {
+            format!(
+                "{}:{}",
+                self.config.metrics_address, self.config.metrics_port
+            )
+        };
+
+        log::info!("Metrics server running on {}", address);
+
+        self.listener = Some(TcpListener::bind(address).await?);
+        Ok(())
+    }
+
+    pub async fn run(mut self, stop_event: event::Event) -> Result<()> {
+        self.listen().await?;
+        let listener = self.listener.take().context("Listener not bound")?;
+
+        loop {
+            let stream;
+            let check_stop_event = stop_event.clone();
+            tokio::select! {
+                _ = check_stop_event => {
+                    break;
+                }
+                listener = listener.accept() => {
+                    stream = listener?.0;
+                }
+            };
+
+            let config = self.config.clone();
+            let stats = self.stats.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve(stream, &config, &stats).await {
+                    log::debug!("METRICS error: {:?}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+async fn serve(mut stream: TcpStream, config: &config::Config, stats: &stats::Stats) -> Result<()> {
+    let src_ip = stream.peer_addr()?.ip().to_canonical();
+    let request = http::read_request(&mut stream, config.command_timeout).await?;
+
+    let (status, body) = if !config.allow.is_empty() && !config.allow.contains(src_ip) {
+        log::info!("METRICS forbidden from {}", src_ip);
+        ("403 Forbidden", String::new())
+    } else if request.method != "GET" {
+        ("405 Method Not Allowed", String::new())
+    } else if request.path != "/metrics" {
+        ("404 Not Found", String::new())
+    } else {
+        ("200 OK", render(stats))
+    };
+
+    http::write_response(&mut stream, status, CONTENT_TYPE, &body).await
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+fn write_histogram(out: &mut String, name: &str, help: &str, histogram: &stats::Histogram) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} histogram");
+    for (bound, count) in histogram.get_buckets() {
+        let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}");
+    }
+    let _ = writeln!(
+        out,
+        "{name}_bucket{{le=\"+Inf\"}} {}",
+        histogram.get_count()
+    );
+    let _ = writeln!(out, "{name}_sum {}", histogram.get_sum().as_secs_f64());
+    let _ = writeln!(out, "{name}_count {}", histogram.get_count());
+}
+
+/// Renders the stats in prometheus text exposition format
+pub fn render(stats: &stats::Stats) -> String {
+    let mut out = String::new();
+    write_metric(
+        &mut out,
+        "udstunnel_connections_total",
+        "counter",
+        "Total accepted connections",
+        stats.get_globals_connections(),
+    );
+    write_metric(
+        &mut out,
+        "udstunnel_concurrent_connections",
+        "gauge",
+        "Currently running relays",
+        stats.get_concurrent_connections(),
+    );
+    write_metric(
+        &mut out,
+        "udstunnel_sent_bytes_total",
+        "counter",
+        "Bytes sent to clients",
+        stats.get_sent_bytes(),
+    );
+    write_metric(
+        &mut out,
+        "udstunnel_received_bytes_total",
+        "counter",
+        "Bytes received from clients",
+        stats.get_recv_bytes(),
+    );
+    write_metric(
+        &mut out,
+        "udstunnel_uptime_seconds",
+        "gauge",
+        "Seconds since the tunnel server started",
+        stats.get_duration().as_secs(),
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP udstunnel_throttled_seconds_total Time relays waited due to bandwidth limits"
+    );
+    let _ = writeln!(out, "# TYPE udstunnel_throttled_seconds_total counter");
+    for (direction, throttled) in [
+        ("download", stats.get_throttled_send()),
+        ("upload", stats.get_throttled_recv()),
+    ] {
+        let _ = writeln!(
+            out,
+            "udstunnel_throttled_seconds_total{{direction=\"{direction}\"}} {}",
+            throttled.as_secs_f64()
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP udstunnel_failures_total Failed connections by stage and reason"
+    );
+    let _ = writeln!(out, "# TYPE udstunnel_failures_total counter");
+    for ((stage, reason), count) in stats.get_failures() {
+        let _ = writeln!(
+            out,
+            "udstunnel_failures_total{{stage=\"{stage}\",reason=\"{reason}\"}} {count}"
+        );
+    }
+
+    write_histogram(
+        &mut out,
+        "udstunnel_uds_request_duration_seconds",
+        "Latency of the requests to the UDS broker",
+        stats.uds_latency(),
+    );
+    write_histogram(
+        &mut out,
+        "udstunnel_relay_duration_seconds",
+        "Duration of the finished relays",
+        stats.relay_duration(),
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let stats = stats::Stats::new();
+        stats.add_global_connection();
+        stats.add_send_bytes(1234);
+        stats.add_failure("handshake", "timeout");
+        stats.uds_latency().observe(Duration::from_millis(20));
+        stats.add_throttled_recv(Duration::from_millis(1500));
+
+        let output = render(&stats);
+        assert!(output.contains("udstunnel_connections_total 1\n"));
+        assert!(output.contains("udstunnel_sent_bytes_total 1234\n"));
+        assert!(
+            output.contains("udstunnel_failures_total{stage=\"handshake\",reason=\"timeout\"} 1\n")
+        );
+        assert!(output.contains("udstunnel_uds_request_duration_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(output.contains("udstunnel_uds_request_duration_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(output.contains("udstunnel_uds_request_duration_seconds_count 1\n"));
+        assert!(output.contains("# TYPE udstunnel_relay_duration_seconds histogram\n"));
+        assert!(output.contains("udstunnel_throttled_seconds_total{direction=\"upload\"} 1.5\n"));
+        assert!(output.contains("udstunnel_throttled_seconds_total{direction=\"download\"} 0\n"));
+    }
+}
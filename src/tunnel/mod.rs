@@ -1,13 +1,27 @@
 pub mod client;
 pub mod config;
 pub mod consts;
+pub mod daemon;
 pub mod error;
 pub mod log;
 pub mod server;
 pub mod types;
 
 pub mod relay;
+pub mod connect;
+pub mod balance;
+pub mod buffers;
+#[cfg(target_os = "linux")]
+pub mod splice;
+pub mod sessions;
 pub mod udsapi;
 pub mod stats;
+pub mod metrics;
+pub mod proxy_protocol;
+pub mod limiter;
+pub mod throttle;
+pub mod admin;
+pub mod audit;
+pub(crate) mod http;
 
 pub mod event;
@@ -0,0 +1,236 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// PROXY protocol, as described in https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107; // Including the final CRLF
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// How PROXY protocol headers are handled on the tunnel listener
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ProxyProtocol {
+    #[default]
+    Off,
+    Accept,  // Header is used if present and sent by a trusted proxy
+    Require, // Every connection must come from a trusted proxy and include the header
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" | "false" | "no" | "" => Ok(ProxyProtocol::Off),
+            "accept" => Ok(ProxyProtocol::Accept),
+            "require" | "true" | "yes" => Ok(ProxyProtocol::Require),
+            _ => Err("Invalid proxy_protocol, valid are off, accept and require"),
+        }
+    }
+}
+
+/// If `byte` can be the start of a PROXY protocol header (v1 or v2).
+/// Tunnel handshake starts with a different byte, so this is enough to tell them apart
+pub fn is_header_start(byte: u8) -> bool {
+    byte == V1_PREFIX[0] || byte == V2_SIGNATURE[0]
+}
+
+/// Reads a PROXY protocol header (v1 or v2), consuming nothing beyond it.
+/// Returns the source address of the original client, or None if the proxy
+/// does not provide one (v1 UNKNOWN, v2 LOCAL or non TCP families, i.e. health checks)
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    match reader.read_u8().await? {
+        b'P' => read_v1(reader).await,
+        b'\r' => read_v2(reader).await,
+        _ => Err(invalid("Not a PROXY protocol header")),
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    // Byte by byte, so nothing after the header is consumed
+    let mut line = vec![b'P'];
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(reader.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY v1 header is not valid text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, _dst, src_port, _dst_port] => {
+            let ip = IpAddr::from_str(src).map_err(|_| invalid("Invalid PROXY v1 address"))?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                return Err(invalid("PROXY v1 address does not match family"));
+            }
+            let port = src_port
+                .parse::<u16>()
+                .map_err(|_| invalid("Invalid PROXY v1 port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("Invalid PROXY v1 header")),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0u8; 16];
+    header[0] = b'\r';
+    reader.read_exact(&mut header[1..]).await?;
+    if &header[..12] != V2_SIGNATURE {
+        return Err(invalid("Invalid PROXY v2 signature"));
+    }
+    let version_command = header[12];
+    let family = header[13];
+    let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY protocol version"));
+    }
+    // Addresses are followed by optional TLVs, that are read and ignored
+    let mut payload = vec![0u8; length];
+    reader.read_exact(&mut payload).await?;
+
+    match version_command & 0x0F {
+        0x00 => return Ok(None), // LOCAL, connection from the proxy itself
+        0x01 => {}               // PROXY
+        _ => return Err(invalid("Unsupported PROXY v2 command")),
+    }
+
+    match family {
+        // TCP over IPv4: src addr(4), dst addr(4), src port(2), dst port(2)
+        0x11 => {
+            if payload.len() < 12 {
+                return Err(invalid("PROXY v2 header too short"));
+            }
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&payload[..4]).unwrap());
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // TCP over IPv6: src addr(16), dst addr(16), src port(2), dst port(2)
+        0x21 => {
+            if payload.len() < 36 {
+                return Err(invalid("PROXY v2 header too short"));
+            }
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[..16]).unwrap());
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(ip), port)))
+        }
+        // UNSPEC, UDP or unix sockets, no usable client address
+        _ => Ok(None),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn test_proxy_protocol_from_str() {
+        assert_eq!("".parse::<ProxyProtocol>(), Ok(ProxyProtocol::Off));
+        assert_eq!("Accept".parse::<ProxyProtocol>(), Ok(ProxyProtocol::Accept));
+        assert_eq!(
+            "require".parse::<ProxyProtocol>(),
+            Ok(ProxyProtocol::Require)
+        );
+        assert!("other".parse::<ProxyProtocol>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_v1() {
+        let mut data: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nHANDSHAKE";
+        let addr = read_header(&mut data).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        // Nothing after the header is consumed
+        assert_eq!(data, b"HANDSHAKE");
+
+        let mut data: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n";
+        let addr = read_header(&mut data).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:4000".parse().unwrap()));
+
+        let mut data: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut data).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_v1_invalid() {
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"[..],
+            b"PROXY TCP4 2001:db8::1 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 99999 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+            &[b'P'; 200],
+        ] {
+            let mut data = header;
+            assert!(read_header(&mut data).await.is_err(), "{:?}", header);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_v2() {
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        addresses.extend_from_slice(&56324u16.to_be_bytes());
+        addresses.extend_from_slice(&443u16.to_be_bytes());
+        // A TLV, ignored
+        addresses.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        let mut header = v2_header(0x01, 0x11, &addresses);
+        header.extend_from_slice(b"HANDSHAKE");
+        let mut data = header.as_slice();
+        let addr = read_header(&mut data).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(data, b"HANDSHAKE");
+
+        let mut addresses = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addresses.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&4000u16.to_be_bytes());
+        addresses.extend_from_slice(&443u16.to_be_bytes());
+        let header = v2_header(0x01, 0x21, &addresses);
+        let addr = read_header(&mut header.as_slice()).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:4000".parse().unwrap()));
+
+        // LOCAL, i.e. health check from the proxy
+        let header = v2_header(0x00, 0x00, &[]);
+        assert_eq!(read_header(&mut header.as_slice()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_v2_invalid() {
+        // Truncated addresses
+        let header = v2_header(0x01, 0x11, &[192, 0, 2, 1]);
+        assert!(read_header(&mut header.as_slice()).await.is_err());
+        // Wrong version
+        let mut header = v2_header(0x01, 0x11, &[0; 12]);
+        header[12] = 0x11;
+        assert!(read_header(&mut header.as_slice()).await.is_err());
+        // Wrong signature
+        let mut header = v2_header(0x01, 0x11, &[0; 12]);
+        header[5] = b'X';
+        assert!(read_header(&mut header.as_slice()).await.is_err());
+    }
+
+    #[test]
+    fn test_is_header_start() {
+        assert!(is_header_start(b'P'));
+        assert!(is_header_start(b'\r'));
+        assert!(!is_header_start(crate::tunnel::consts::HANDSHAKE_V1[0]));
+    }
+}
@@ -1,13 +1,30 @@
-use std::{io, sync::Arc};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    time::Instant,
 };
 use tokio_rustls::server::TlsStream;
 
 use anyhow::Result;
 
-use super::{consts, event, stats, types, udsapi};
+#[cfg(target_os = "linux")]
+use super::splice;
+use super::{
+    audit, balance, buffers, connect, error, event, sessions, stats, throttle, types, udsapi,
+};
+#[cfg(target_os = "linux")]
+use crate::tls::ktls;
 
 pub struct RelayConnection {
     pub tunnel_id: String,
@@ -15,11 +32,37 @@ pub struct RelayConnection {
     pub udsapi: Arc<dyn udsapi::UDSApiProvider>,
 
     pub src: String, // Source IP/Port
+    // Real client address (i.e. from PROXY protocol). If not set, the peer address of the stream
+    pub src_addr: Option<std::net::SocketAddr>,
     pub dst: String, // Destination IP/Port
     pub notify_ticket: Option<String>,
 
     pub global_stats: Arc<stats::Stats>,
     pub local_stats: Arc<stats::Stats>,
+
+    pub sessions: Arc<sessions::SessionRegistry>,
+    pub audit: Option<Arc<audit::AuditLog>>,
+    // Subject of the client certificate (mTLS), sent to the broker with the ticket
+    pub client_subject: Option<String>,
+
+    // Limits of the relay, zero means no limit. The ticket can override them
+    pub idle_timeout: Duration,
+    pub max_session_duration: Duration,
+    // Why the relay was closed, sent to the broker on the end notification
+    pub close_reason: &'static str,
+
+    // Global bandwidth limits, and the ones of this session (bytes per second, 0 means no limit).
+    // The ticket can override the session ones
+    pub bandwidth: Arc<throttle::BandwidthLimits>,
+    pub session_bandwidth_upload: u64,
+    pub session_bandwidth_download: u64,
+
+    // Hand the client encryption to the kernel (kTLS), if supported
+    pub ktls: bool,
+
+    // Connection to the destination server: names resolved to IPv6 addresses only, and timeout
+    pub ipv6: bool,
+    pub connect_timeout: Duration,
 }
 
 impl RelayConnection {
@@ -28,19 +71,77 @@ impl RelayConnection {
         ticket: String,
         udsapi: Arc<dyn udsapi::UDSApiProvider>,
         stats: Arc<stats::Stats>,
+        sessions: Arc<sessions::SessionRegistry>,
     ) -> Self {
         Self {
             tunnel_id,
             ticket,
             udsapi,
             src: String::new(),
+            src_addr: None,
             dst: String::new(),
             notify_ticket: None,
             global_stats: stats.clone(),
             local_stats: Arc::new(stats::Stats::new()),
+            sessions,
+            audit: None,
+            client_subject: None,
+            idle_timeout: Duration::ZERO,
+            max_session_duration: Duration::ZERO,
+            close_reason: "",
+            bandwidth: Arc::new(throttle::BandwidthLimits::default()),
+            session_bandwidth_upload: 0,
+            session_bandwidth_download: 0,
+            ktls: false,
+            ipv6: false,
+            connect_timeout: Duration::from_secs(5),
         }
     }
 
+    pub fn with_src(mut self, src_addr: std::net::SocketAddr) -> Self {
+        self.src_addr = Some(src_addr);
+        self
+    }
+
+    pub fn with_audit(mut self, audit: Option<Arc<audit::AuditLog>>) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    pub fn with_client_subject(mut self, client_subject: Option<String>) -> Self {
+        self.client_subject = client_subject;
+        self
+    }
+
+    pub fn with_timeouts(mut self, idle_timeout: Duration, max_session_duration: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self.max_session_duration = max_session_duration;
+        self
+    }
+
+    pub fn with_bandwidth(
+        mut self,
+        bandwidth: Arc<throttle::BandwidthLimits>,
+        session_upload: u64,
+        session_download: u64,
+    ) -> Self {
+        self.bandwidth = bandwidth;
+        self.session_bandwidth_upload = session_upload;
+        self.session_bandwidth_download = session_download;
+        self
+    }
+
+    pub fn with_ktls(mut self, ktls: bool) -> Self {
+        self.ktls = ktls;
+        self
+    }
+
+    pub fn with_connect(mut self, ipv6: bool, connect_timeout: Duration) -> Self {
+        self.ipv6 = ipv6;
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
     pub(crate) async fn run(
         &mut self,
         mut client_stream: TlsStream<TcpStream>, // move value
@@ -51,6 +152,9 @@ impl RelayConnection {
         // 3.- If ticket is found, we will receive (json):
         // { 'host': '....', 'port': '....', 'notify': '....' }
         // Where host it te host to connect, port is the port to connect and notify is the UDS ticket used to notification
+        // Instead of host and port, it can have a list of destinations, tried until one connects:
+        // { 'destinations': [{ 'host': '....', 'port': '....', 'weight': 1 }, ...], 'balance': 'ordered' }
+        // (balance can be ordered, weighted or least_connections)
         let src_ip = match self.set_src(&client_stream) {
             Ok(ip) => ip,
             Err(e) => {
@@ -59,13 +163,30 @@ impl RelayConnection {
             }
         };
 
-        let uds_response = if let Ok(response) = self.udsapi.get_ticket(&self.ticket, &src_ip).await
-        {
-            log::debug!("UDS Response: {:?}", response);
-            response
-        } else {
-            log::error!("Error requesting UDS");
-            return Err(anyhow::anyhow!("Error requesting UDS"));
+        let uds_start = std::time::Instant::now();
+        let uds_response = self
+            .udsapi
+            .get_ticket(&self.ticket, &src_ip, self.client_subject.as_deref())
+            .await;
+        self.global_stats.uds_latency().observe(uds_start.elapsed());
+        let uds_response = match uds_response {
+            Ok(response) => {
+                log::debug!("UDS Response: {:?}", response);
+                response
+            }
+            Err(e) => {
+                // Rejected by the broker (invalid ticket), or broker not reachable
+                let rejected = e.downcast_ref::<error::UDSError>().is_some();
+                self.global_stats
+                    .add_failure("ticket", if rejected { "invalid" } else { "error" });
+                log::error!("Error requesting UDS: {}", e);
+                client_stream
+                    .write_all(types::Response::TicketError.to_bytes())
+                    .await
+                    .unwrap_or_default();
+                client_stream.shutdown().await.unwrap_or_default();
+                return Err(e);
+            }
         };
 
         // If host starts with #, it's a command, process it and return
@@ -85,23 +206,56 @@ impl RelayConnection {
             }
         }
 
-        self.dst = format!("{}:{}", uds_response.host, uds_response.port);
+        // Destinations in the order they will be tried, the first one is shown until connected
+        let destinations = balance::candidates(
+            uds_response.destinations(),
+            uds_response.balance.unwrap_or_default(),
+            &self.sessions,
+        );
+        self.dst = destinations
+            .first()
+            .map(udsapi::Destination::address)
+            .unwrap_or_default();
         self.notify_ticket = Some(uds_response.notify);
+        if let Some(idle_timeout) = uds_response.idle_timeout {
+            self.idle_timeout = Duration::from_secs(idle_timeout);
+        }
+        if let Some(max_session_duration) = uds_response.max_session_duration {
+            self.max_session_duration = Duration::from_secs(max_session_duration);
+        }
+        if let Some(bandwidth_upload) = uds_response.bandwidth_upload {
+            self.session_bandwidth_upload = bandwidth_upload;
+        }
+        if let Some(bandwidth_download) = uds_response.bandwidth_download {
+            self.session_bandwidth_download = bandwidth_download;
+        }
 
         // Open the connection to the destination server (server stream)
-        let server = format!("{}:{}", uds_response.host, uds_response.port);
 
         log::info!(
+            event = "open", tunnel_id = self.tunnel_id, src = self.src, dst = self.dst;
             "OPEN TUNNEL ({}) FROM {} to {}",
             self.tunnel_id,
             self.src,
             self.dst
         );
 
-        let server_stream = match TcpStream::connect(server.clone()).await {
+        let server_stream = match self.connect_server(destinations).await {
             Ok(stream) => stream,
             Err(e) => {
-                log::error!("CONNECTION FAILED ({}): {:?}", self.tunnel_id, e);
+                let reason = if e.kind() == io::ErrorKind::TimedOut {
+                    "timeout"
+                } else {
+                    "error"
+                };
+                self.global_stats.add_failure("connect", reason);
+                log::error!(
+                    event = "connect_error",
+                    tunnel_id = self.tunnel_id,
+                    src = self.src,
+                    dst = self.dst;
+                    "CONNECTION FAILED ({}): {:?}", self.tunnel_id, e
+                );
                 client_stream
                     .write_all(types::Response::ConnectError.to_bytes())
                     .await
@@ -110,63 +264,111 @@ impl RelayConnection {
                 return Err(anyhow::anyhow!(e));
             }
         };
-        client_stream
+
+        // Split the client stream into reader and writer. Done before the OK response, so the
+        // client has sent nothing since the command (and nothing is left on the rustls buffers)
+        let (mut client_reader, mut client_writer) = match self.split_client(client_stream).await {
+            Ok(halves) => halves,
+            Err(e) => {
+                self.global_stats.add_failure("ktls", "error");
+                log::error!("Error setting up kernel TLS ({}): {:?}", self.tunnel_id, e);
+                return Err(anyhow::anyhow!(e));
+            }
+        };
+        client_writer
             .write_all(types::Response::Ok.to_bytes())
             .await
             .unwrap();
 
-        let (mut server_reader, mut server_writer) = server_stream.into_split();
-
-        // Split the client stream into reader and writer
-        let (mut client_reader, mut client_writer) = tokio::io::split(client_stream);
+        let (server_reader, mut server_writer) = server_stream.into_split();
 
         // Current connections counter
         self.global_stats.add_concurrent_connection();
 
         let local_tasks_stopper = event::Event::new();
 
+        // Register the relay, so it can be listed and terminated
+        self.sessions.register(
+            sessions::Session::new(
+                &self.tunnel_id,
+                &self.src,
+                &self.dst,
+                &self.ticket,
+                self.local_stats.clone(),
+            )
+            .with_notify_ticket(self.notify_ticket.as_deref().unwrap_or_default())
+            .with_stopper(local_tasks_stopper.clone()),
+        );
+
+        // Last time data was relayed (in either direction), as milliseconds since relay start.
+        // Waits for the bandwidth are not idle time, so they move it to the end of the wait
+        let started = Instant::now();
+        let last_activity = Arc::new(AtomicU64::new(0));
+
+        // Reads wait while the global or the session bandwidth is exhausted
+        let upload = throttle::Throttle::new([
+            self.bandwidth.upload.clone(),
+            throttle::Bandwidth::new(self.session_bandwidth_upload),
+        ]);
+        let download = throttle::Throttle::new([
+            self.bandwidth.download.clone(),
+            throttle::Bandwidth::new(self.session_bandwidth_download),
+        ]);
+
         let global_stats = self.global_stats.clone();
         let local_stats = self.local_stats.clone();
 
         let global_stopper = stop_event.clone();
         let local_stopper = local_tasks_stopper.clone();
+        let activity = last_activity.clone();
         let server_to_client = tokio::task::spawn(async move {
-            let mut buf = vec![0; consts::BUFFER_SIZE];
+            // Buffers are taken from the pool only when the server has sent something
+            let mut read_size = buffers::AdaptiveSize::new();
             log::debug!("Starting server_to_client task");
+            // Returns why the relay is being closed
             loop {
                 tokio::select! {
                     _ = global_stopper.clone() => {
                         log::debug!("Stopping server_to_client task");
-                        break;
+                        break "shutdown";
                     }
                     _ = local_stopper.clone() => {
                         log::debug!("Stopping server_to_client task");
-                        break;
+                        break "terminated";
                     }
-                    read_result = server_reader.read(&mut buf) => {
-                        match read_result {
+                    (throttled, relay_result) = async {
+                        let throttled = download
+                            .ready(|wait| mark_activity(&activity, started.elapsed() + wait))
+                            .await;
+                        (throttled, client_writer.relay_from(&server_reader, &mut read_size).await)
+                    } => {
+                        if !throttled.is_zero() {
+                            global_stats.add_throttled_send(throttled);
+                            local_stats.add_throttled_send(throttled);
+                        }
+                        match relay_result {
                             Ok(0) => {
-                                break;
+                                // Half-close, the client can keep sending
+                                client_writer.shutdown().await.unwrap_or_else(|e| {
+                                    log::debug!("Error closing client write side: {:?}", e);
+                                });
+                                break "server_closed";
                             }
                             Ok(n) => {
                                 // Ad to global and local stats
                                 global_stats.add_send_bytes(n as u64);
                                 local_stats.add_send_bytes(n as u64);
-
-                                let mut error: Option<()> = None;
-                                client_writer.write_all(&buf[..n]).await.unwrap_or_else(|_| error = Some(()));
-                                if error.is_some() {
-                                    log::error!("ERROR writing to client");
-                                    break;
-                                }
+                                download.consume(n);
+                                mark_activity(&activity, started.elapsed());
                             }
-                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                                continue;
+                            Err(RelayError::Write(e)) => {
+                                log::error!("ERROR writing to client: {:?}", e);
+                                break "client_error";
                             }
-                            Err(e) => {
+                            Err(RelayError::Read(e)) => {
                                 // Last one, move value
                                 log::error!("ERROR from server: {:?}", e);
-                                break;
+                                break "server_error";
                             }
                         }
                     }
@@ -179,65 +381,115 @@ impl RelayConnection {
 
         let global_stopper = stop_event.clone();
         let local_stopper = local_tasks_stopper.clone();
+        let activity = last_activity.clone();
 
         let client_to_server = tokio::task::spawn(async move {
-            let mut buf = vec![0; consts::BUFFER_SIZE];
+            let mut read_size = buffers::AdaptiveSize::new();
             log::debug!("Starting client_to_server task");
+            // Returns why the relay is being closed
             loop {
                 tokio::select! {
                     _ = global_stopper.clone() => {
                         log::debug!("Stopping client_to_server task");
-                        break;
+                        break "shutdown";
                     }
                     _ = local_stopper.clone() => {
                         log::debug!("Stopping client_to_server task");
-                        break;
+                        break "terminated";
                     }
-                    read_result = client_reader.read(&mut buf) => {
-                        match read_result {
+                    (throttled, relay_result) = async {
+                        let throttled = upload
+                            .ready(|wait| mark_activity(&activity, started.elapsed() + wait))
+                            .await;
+                        (throttled, client_reader.relay_to(&mut server_writer, &mut read_size).await)
+                    } => {
+                        if !throttled.is_zero() {
+                            global_stats.add_throttled_recv(throttled);
+                            local_stats.add_throttled_recv(throttled);
+                        }
+                        match relay_result {
                             Ok(0) => {
-                                break;
+                                // Half-close, the server can keep sending
+                                server_writer.shutdown().await.unwrap_or_else(|e| {
+                                    log::debug!("Error closing server write side: {:?}", e);
+                                });
+                                break "client_closed";
                             }
                             Ok(n) => {
                                 // Ad to global and local stats
                                 global_stats.add_recv_bytes(n as u64);
                                 local_stats.add_recv_bytes(n as u64);
-                                let mut error: Option<()> = None;
-                                server_writer.write_all(&buf[..n]).await.unwrap_or_else(|_| error = Some(()));
-                                if error.is_some() {
-                                    log::error!("ERROR writing to server");
-                                    break;
-                                }
+                                upload.consume(n);
+                                mark_activity(&activity, started.elapsed());
+                            }
+                            Err(RelayError::Write(e)) => {
+                                log::error!("ERROR writing to server: {:?}", e);
+                                break "server_error";
                             }
-                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            Err(RelayError::Read(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {
                                 continue;
                             }
-                            Err(e) => {
+                            Err(RelayError::Read(e)) => {
                                 // Last one, move value
                                 log::error!("ERROR from client: {:?}", e);
-                                break;
+                                break "client_error";
                             }
                         }
                     }
                 }
             }
         });
-        // As soon as one of the tasks completes, the other task will be cancelled
+        // A side closing (EOF) only closes its direction, the other one keeps relaying until it is
+        // also closed. Anything else (errors, stop, timeouts) ends both, cancelling the other task.
+        // The reason of a relay closed by both sides is the one of the side that closed first
+        let mut client_to_server = client_to_server;
+        let mut server_to_client = server_to_client;
+        let max_session_duration = wait_for(self.max_session_duration);
+        tokio::pin!(max_session_duration);
+        let mut client_closed = false;
+        let mut server_closed = false;
+        let mut half_closed: Option<&'static str> = None;
 
         log::debug!("Waiting for any to complete");
-        tokio::select! {
-            res = client_to_server => {
-                log::debug!("client_to_server task completed: {:?}", res);
+        self.close_reason = loop {
+            let reason = tokio::select! {
+                res = &mut client_to_server, if !client_closed => {
+                    log::debug!("client_to_server task completed: {:?}", res);
+                    client_closed = true;
+                    res.unwrap_or("error")
+                }
+                res = &mut server_to_client, if !server_closed => {
+                    log::debug!("Write task completed: {:?}", res);
+                    server_closed = true;
+                    res.unwrap_or("error")
+                }
+                _ = wait_idle(started, &last_activity, self.idle_timeout) => {
+                    log::debug!("Relay idle for {:?}", self.idle_timeout);
+                    break "idle_timeout";
+                }
+                _ = &mut max_session_duration => {
+                    log::debug!("Relay reached max session duration {:?}", self.max_session_duration);
+                    break "max_session_duration";
+                }
+            };
+            if !matches!(reason, "client_closed" | "server_closed") {
+                break reason;
             }
-            res = server_to_client => {
-                log::debug!("Write task completed: {:?}", res);
+            if let Some(first) = half_closed {
+                break first;
             }
-        }
+            log::debug!("Relay half closed ({}): {}", self.tunnel_id, reason);
+            half_closed = Some(reason);
+        };
         // Ensure the other task is also stopped
         local_tasks_stopper.set().unwrap();
 
         // And update the concurrent connections
         self.global_stats.sub_concurrent_connection();
+        self.sessions.unregister(&self.tunnel_id);
+        self.global_stats
+            .relay_duration()
+            .observe(self.local_stats.get_duration());
 
         // As soon as one or the tasks is completed, the connection will be closed
         // Ant the other task will be cancelled
@@ -254,29 +506,46 @@ impl RelayConnection {
     async fn notify_end(&mut self) -> Result<()> {
         if let Some(notify_ticket) = self.notify_ticket.take() {
             log::info!(
-                "TERMINATED ({}) {} to {}, s:{}, r:{}, t:{}",
+                event = "terminated",
+                tunnel_id = self.tunnel_id,
+                src = self.src,
+                dst = self.dst,
+                sent = self.local_stats.get_sent_bytes(),
+                recv = self.local_stats.get_recv_bytes(),
+                duration = self.local_stats.get_duration().as_secs(),
+                reason = self.close_reason;
+                "TERMINATED ({}) {} to {}, s:{}, r:{}, t:{}, reason:{}",
                 self.tunnel_id,
                 self.src,
                 self.dst,
                 self.local_stats.get_sent_bytes(),
                 self.local_stats.get_recv_bytes(),
-                self.local_stats.get_duration().as_secs()
+                self.local_stats.get_duration().as_secs(),
+                self.close_reason
             );
+            self.write_audit();
             // Send the notification to UDS
-            self.udsapi
+            let uds_start = std::time::Instant::now();
+            let result = self
+                .udsapi
                 .notify_end(
                     &notify_ticket,
                     self.local_stats.get_sent_bytes(),
                     self.local_stats.get_recv_bytes(),
                     self.local_stats.get_duration(),
+                    self.close_reason,
                 )
-                .await
-                .map_err(|e| {
-                    log::error!("Error notifying end to UDS: {:?}", e);
-                    e
-                })?;
+                .await;
+            self.global_stats.uds_latency().observe(uds_start.elapsed());
+            result.map_err(|e| {
+                log::error!("Error notifying end to UDS: {:?}", e);
+                e
+            })?;
         } else {
-            log::info!("TERMINATED ({}) {}", self.tunnel_id, self.src);
+            log::info!(
+                event = "terminated", tunnel_id = self.tunnel_id, src = self.src;
+                "TERMINATED ({}) {}", self.tunnel_id, self.src
+            );
         }
         Ok(())
 
@@ -284,6 +553,100 @@ impl RelayConnection {
         // self.owner.finished.set();
     }
 
+    fn write_audit(&self) {
+        let Some(audit) = self.audit.as_ref() else {
+            return;
+        };
+        let end = std::time::SystemTime::now();
+        let duration = self.local_stats.get_duration();
+        let record = audit::AuditRecord {
+            event: "terminated".to_string(),
+            tunnel_id: self.tunnel_id.clone(),
+            src: self.src.clone(),
+            dst: self.dst.clone(),
+            ticket: self
+                .ticket
+                .chars()
+                .take(sessions::TICKET_PREFIX_LENGTH)
+                .collect(),
+            start: audit::unix_timestamp(end - duration),
+            end: audit::unix_timestamp(end),
+            duration: duration.as_secs(),
+            sent_bytes: self.local_stats.get_sent_bytes(),
+            recv_bytes: self.local_stats.get_recv_bytes(),
+        };
+        if let Err(e) = audit.append(&record) {
+            log::error!("Error writing audit record ({}): {:?}", self.tunnel_id, e);
+        }
+    }
+
+    // Connects to the first destination that answers, trying them in order.
+    // connect_timeout is for all of them, not for each one.
+    // The one connected to (or the last one tried) is kept as dst
+    async fn connect_server(
+        &mut self,
+        destinations: Vec<udsapi::Destination>,
+    ) -> io::Result<TcpStream> {
+        let deadline = Instant::now() + self.connect_timeout;
+        let mut destinations = destinations.into_iter().peekable();
+        while let Some(destination) = destinations.next() {
+            self.dst = destination.address();
+            match connect::connect(
+                &destination.host,
+                destination.port,
+                self.ipv6,
+                deadline.saturating_duration_since(Instant::now()),
+            )
+            .await
+            {
+                Ok(stream) => return Ok(stream),
+                // Last one, or no time left for the next ones
+                Err(e) if destinations.peek().is_none() || Instant::now() >= deadline => {
+                    return Err(e)
+                }
+                Err(e) => {
+                    self.global_stats.add_failure("connect", "failover");
+                    log::warn!(
+                        event = "connect_failover",
+                        tunnel_id = self.tunnel_id,
+                        src = self.src,
+                        dst = self.dst;
+                        "CONNECTION FAILED ({}) to {}, trying next destination: {:?}",
+                        self.tunnel_id,
+                        self.dst,
+                        e
+                    );
+                }
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::NotFound, "No destinations"))
+    }
+
+    // Splits the client stream, handing its encryption to the kernel if enabled and supported
+    async fn split_client(
+        &self,
+        mut client_stream: TlsStream<TcpStream>,
+    ) -> io::Result<(ClientReader, ClientWriter)> {
+        #[cfg(target_os = "linux")]
+        if self.ktls {
+            // Anything pending (i.e. session tickets) must be sent before
+            client_stream.flush().await?;
+            match ktls::offload(client_stream)? {
+                ktls::Offload::Kernel(stream) => {
+                    log::debug!("Relay ({}) on kernel TLS", self.tunnel_id);
+                    let (reader, writer) = stream.into_split();
+                    return Ok((
+                        ClientReader::Kernel(reader, splice::Pipe::new()?),
+                        ClientWriter::Kernel(writer, splice::Pipe::new()?),
+                    ));
+                }
+                ktls::Offload::Userspace(stream) => client_stream = *stream,
+            }
+        }
+        let (reader, writer) = tokio::io::split(client_stream);
+        Ok((ClientReader::Tls(reader), ClientWriter::Tls(writer)))
+    }
+
     async fn execute_command(&self, command: &str) -> Option<String> {
         match command {
             "close" => None,
@@ -295,7 +658,7 @@ impl RelayConnection {
     }
 
     fn set_src(&mut self, client_stream: &TlsStream<TcpStream>) -> Result<String, &'static str> {
-        let src_peer_addr =
+        let src_peer_addr = self.src_addr.unwrap_or_else(|| {
             client_stream
                 .get_ref()
                 .0
@@ -303,7 +666,8 @@ impl RelayConnection {
                 .unwrap_or(std::net::SocketAddr::new(
                     std::net::IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)),
                     0,
-                ));
+                ))
+        });
         if src_peer_addr.ip().is_unspecified() {
             return Err("Error getting peer address");
         }
@@ -317,3 +681,141 @@ impl RelayConnection {
         Ok(src_ip)
     }
 }
+
+// Error relaying a burst of data, on its source (read) or its destination (write)
+enum RelayError {
+    Read(io::Error),
+    Write(io::Error),
+}
+
+// Client side of a relay. On kernel TLS the client socket carries plaintext, so data is spliced
+// from/to the server socket without going through userspace
+enum ClientReader {
+    Tls(ReadHalf<TlsStream<TcpStream>>),
+    #[cfg(target_os = "linux")]
+    Kernel(OwnedReadHalf, splice::Pipe),
+}
+
+enum ClientWriter {
+    Tls(WriteHalf<TlsStream<TcpStream>>),
+    #[cfg(target_os = "linux")]
+    Kernel(OwnedWriteHalf, splice::Pipe),
+}
+
+impl ClientReader {
+    // Relays the next burst of data from the client to the server. 0 means closed by the client
+    async fn relay_to(
+        &mut self,
+        server: &mut OwnedWriteHalf,
+        size: &mut buffers::AdaptiveSize,
+    ) -> Result<usize, RelayError> {
+        match self {
+            ClientReader::Tls(reader) => {
+                let mut buf = buffers::pool().get(size.get());
+                let n = reader.read(&mut buf).await.map_err(RelayError::Read)?;
+                if n > 0 {
+                    size.record(n);
+                    server
+                        .write_all(&buf[..n])
+                        .await
+                        .map_err(RelayError::Write)?;
+                }
+                Ok(n)
+            }
+            #[cfg(target_os = "linux")]
+            ClientReader::Kernel(reader, pipe) => {
+                let n = match pipe.fill(reader, size.get()).await {
+                    Ok(n) => n,
+                    // A control record is next, only close_notify is expected
+                    Err(e) if matches!(e.raw_os_error(), Some(libc::EINVAL | libc::EIO)) => {
+                        ktls::read_close_notify(reader.as_ref()).map_err(RelayError::Read)?;
+                        0
+                    }
+                    Err(e) => return Err(RelayError::Read(e)),
+                };
+                size.record(n);
+                pipe.drain(server).await.map_err(RelayError::Write)?;
+                Ok(n)
+            }
+        }
+    }
+}
+
+impl ClientWriter {
+    // Relays the next burst of data from the server to the client. 0 means closed by the server
+    async fn relay_from(
+        &mut self,
+        server: &OwnedReadHalf,
+        size: &mut buffers::AdaptiveSize,
+    ) -> Result<usize, RelayError> {
+        match self {
+            ClientWriter::Tls(writer) => {
+                let chunks = buffers::read_chunks(server, size)
+                    .await
+                    .map_err(RelayError::Read)?;
+                buffers::write_chunks(writer, &chunks)
+                    .await
+                    .map_err(RelayError::Write)?;
+                Ok(chunks.len())
+            }
+            #[cfg(target_os = "linux")]
+            ClientWriter::Kernel(writer, pipe) => {
+                let n = pipe
+                    .fill(server, size.get())
+                    .await
+                    .map_err(RelayError::Read)?;
+                size.record(n);
+                pipe.drain(writer).await.map_err(RelayError::Write)?;
+                Ok(n)
+            }
+        }
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            ClientWriter::Tls(writer) => writer.write_all(buf).await,
+            #[cfg(target_os = "linux")]
+            ClientWriter::Kernel(writer, _) => writer.write_all(buf).await,
+        }
+    }
+
+    // Sends close_notify and closes the write side, the client can keep sending
+    async fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            ClientWriter::Tls(writer) => writer.shutdown().await,
+            #[cfg(target_os = "linux")]
+            ClientWriter::Kernel(writer, _) => {
+                ktls::send_close_notify(writer.as_ref()).await?;
+                writer.shutdown().await
+            }
+        }
+    }
+}
+
+// Resolves once there has been no activity for `idle_timeout`. Never if zero
+async fn wait_idle(started: Instant, last_activity: &AtomicU64, idle_timeout: Duration) {
+    if idle_timeout.is_zero() {
+        return std::future::pending().await;
+    }
+    loop {
+        let deadline =
+            started + Duration::from_millis(last_activity.load(Ordering::Relaxed)) + idle_timeout;
+        if Instant::now() >= deadline {
+            return;
+        }
+        tokio::time::sleep_until(deadline).await;
+    }
+}
+
+// Moves the last activity forward to `at` (time since relay start), never backwards
+fn mark_activity(last_activity: &AtomicU64, at: Duration) {
+    last_activity.fetch_max(at.as_millis() as u64, Ordering::Relaxed);
+}
+
+// Resolves after `duration`. Never if zero
+async fn wait_for(duration: Duration) {
+    if duration.is_zero() {
+        return std::future::pending().await;
+    }
+    tokio::time::sleep(duration).await;
+}
@@ -1,14 +1,14 @@
 use anyhow::{Context, Result};
 use log;
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use subtle::ConstantTimeEq;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    time::timeout,
+    net::{TcpListener, TcpSocket, TcpStream},
+    time::{timeout, timeout_at, Instant},
 };
 use tokio_rustls::{
     rustls::{
-        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
         version::{TLS12, TLS13},
         ServerConfig,
     },
@@ -19,13 +19,26 @@ use uuid;
 
 use crate::tunnel::{relay, types};
 
-use super::{config, consts, event, stats, udsapi};
+use super::{
+    audit, config, consts, error, event, limiter,
+    proxy_protocol::{self, ProxyProtocol},
+    sessions, stats, throttle, udsapi,
+};
 use crate::tls;
 
 pub struct TunnelServer {
     pub udsapi: Arc<dyn udsapi::UDSApiProvider>,
     pub config: config::Config,
     pub stats: Arc<stats::Stats>,
+    pub sessions: Arc<sessions::SessionRegistry>,
+    pub audit: Option<Arc<audit::AuditLog>>,
+    pub limiter: Arc<limiter::Limiter>,
+    pub bandwidth: Arc<throttle::BandwidthLimits>,
+    // Bound listener and tls acceptor, set by `listen`
+    listener: Option<(TcpListener, TlsAcceptor)>,
+    cert_resolvers: Vec<Arc<tls::cert_resolver::CertResolver>>,
+    // Broker of every virtual host, selected by the SNI of the client
+    pub virtual_hosts: Arc<Vec<(config::VirtualHost, Arc<dyn udsapi::UDSApiProvider>)>>,
 }
 
 // let acceptor = tls_acceptor.clone();
@@ -42,7 +55,12 @@ struct Connection {
     tunnel_id: String,
     config: config::Config,
     udsapi: Arc<dyn udsapi::UDSApiProvider>,
+    virtual_hosts: Arc<Vec<(config::VirtualHost, Arc<dyn udsapi::UDSApiProvider>)>>,
     stats: Arc<stats::Stats>,
+    sessions: Arc<sessions::SessionRegistry>,
+    audit: Option<Arc<audit::AuditLog>>,
+    limiter: Arc<limiter::Limiter>,
+    bandwidth: Arc<throttle::BandwidthLimits>,
     stop_event: event::Event,
 }
 
@@ -51,18 +69,21 @@ impl Connection {
         acceptor: TlsAcceptor,
         stream: TcpStream,
         tunnel_id: String,
-        config: config::Config,
-        udsapi: Arc<dyn udsapi::UDSApiProvider>,
-        stats: Arc<stats::Stats>,
+        server: &TunnelServer,
         stop_event: event::Event,
     ) -> Self {
         Connection {
             acceptor,
             stream: Some(stream),
             tunnel_id,
-            config,
-            udsapi,
-            stats,
+            config: server.config.clone(),
+            udsapi: server.udsapi.clone(),
+            virtual_hosts: server.virtual_hosts.clone(),
+            stats: server.stats.clone(),
+            sessions: server.sessions.clone(),
+            audit: server.audit.clone(),
+            limiter: server.limiter.clone(),
+            bandwidth: server.bandwidth.clone(),
             stop_event,
         }
     }
@@ -73,25 +94,91 @@ impl Connection {
 
         let mut stream = self.stream.take().context("Stream already taken")?;
 
-        let src_ip = stream.peer_addr().unwrap().ip().to_string();
+        let peer_addr = stream.peer_addr().context("Error getting peer address")?;
+        // PROXY protocol header (if any) and handshake must arrive within the handshake timeout
+        let handshake_deadline = Instant::now() + self.config.handshake_timeout;
+
+        // Behind a load balancer, the real client address comes on the PROXY protocol header
+        let src_addr = match self
+            .client_address(&mut stream, peer_addr, handshake_deadline)
+            .await
+        {
+            Ok(src_addr) => src_addr,
+            Err(reason) => {
+                self.stats.add_failure("proxy", reason);
+                log::error!(
+                    event = "error", stage = "PROXY", tunnel_id = self.tunnel_id, src = peer_addr.ip().to_string();
+                    "PROXY ({}) header {} from {}", self.tunnel_id, reason, peer_addr.ip()
+                );
+                stream.shutdown().await.unwrap_or_default(); // Ignore error
+                return Ok(());
+            }
+        };
+        // IPv4 clients on a dual stack listener are seen as ::ffff:a.b.c.d
+        let src_addr = SocketAddr::new(src_addr.ip().to_canonical(), src_addr.port());
+        let src_ip = src_addr.ip().to_string();
 
-        log::info!("CONNECTION ({}) from {}", self.tunnel_id, src_ip);
+        log::info!(
+            event = "connection", tunnel_id = self.tunnel_id, src = src_ip;
+            "CONNECTION ({}) from {}", self.tunnel_id, src_ip
+        );
+        if src_addr.ip() != peer_addr.ip().to_canonical() {
+            log::debug!(
+                "CONNECTION ({}) proxied by {}",
+                self.tunnel_id,
+                peer_addr.ip()
+            );
+        }
+
+        // Banned or too many connections, closed before anything else is done
+        let limited = if self.limiter.is_banned(src_addr.ip()) {
+            Some("banned")
+        } else if !self.limiter.allow(src_addr.ip()) {
+            Some("rate")
+        } else {
+            None
+        };
+        if let Some(reason) = limited {
+            self.stats.add_failure("limit", reason);
+            log::warn!(
+                event = "limited", tunnel_id = self.tunnel_id, src = src_ip, reason = reason;
+                "CONNECTION ({}) from {} rejected: {}", self.tunnel_id, src_ip, reason
+            );
+            stream.shutdown().await.unwrap_or_default(); // Ignore error
+            return Ok(());
+        }
+        let Some(_source_slot) = self.limiter.source_slot(src_addr.ip()) else {
+            self.stats.add_failure("limit", "max_connections_per_ip");
+            log::warn!(
+                event = "limited", tunnel_id = self.tunnel_id, src = src_ip, reason = "max_connections_per_ip";
+                "CONNECTION ({}) from {} rejected: too many connections", self.tunnel_id, src_ip
+            );
+            stream
+                .write_all(types::Response::BusyError.to_bytes())
+                .await
+                .unwrap_or_default();
+            stream.shutdown().await.unwrap_or_default(); // Ignore error
+            return Ok(());
+        };
 
         let mut buf = vec![0u8; consts::HANDSHAKE_V1.len()];
 
         // 1.- Read the handshake (with timeout)
-        let handshake = match timeout(
-            self.config.handshake_timeout,
-            stream.read_exact(&mut buf),
-        )
-        .await
-        {
+        let handshake = match timeout_at(handshake_deadline, stream.read_exact(&mut buf)).await {
             Ok(handshake) => handshake,
             Err(e) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, e)),
         };
 
         // If no valid, even if timeout, close the connection and log the error
         if handshake.is_err() || buf != consts::HANDSHAKE_V1 {
+            self.stats.add_failure(
+                "handshake",
+                match &handshake {
+                    Err(e) if e.kind() == std::io::ErrorKind::TimedOut => "timeout",
+                    Err(_) => "error",
+                    Ok(_) => "invalid",
+                },
+            );
             if handshake.is_err() {
                 stream
                     .write_all(types::Response::TimeoutError.to_bytes())
@@ -119,7 +206,32 @@ impl Connection {
         log::debug!("HANDSHAKE ({}) from {}", self.tunnel_id, src_ip);
 
         // 2.- Upgrade the connection to TLS
-        let mut stream = self.acceptor.accept(stream).await.unwrap();
+        let mut stream = match self.acceptor.accept(stream).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                self.stats.add_failure("tls", "error");
+                log::error!("TLS ({}) error from {}: {}", self.tunnel_id, src_ip, e);
+                return Ok(());
+            }
+        };
+
+        // Broker of the virtual host requested by SNI, if any
+        let udsapi = match stream.get_ref().1.server_name().and_then(|server_name| {
+            self.virtual_hosts
+                .iter()
+                .find(|(virtual_host, _)| virtual_host.matches(server_name))
+        }) {
+            Some((virtual_host, udsapi)) => {
+                log::debug!(
+                    "TLS ({}) virtual host {} for {}",
+                    self.tunnel_id,
+                    virtual_host.name,
+                    src_ip
+                );
+                udsapi.clone()
+            }
+            None => self.udsapi.clone(),
+        };
 
         let command = match TunnelServer::get_command(
             &mut stream,
@@ -131,6 +243,14 @@ impl Connection {
         {
             Ok(command) => command,
             Err(err) => {
+                self.stats.add_failure(
+                    "command",
+                    match err.kind() {
+                        std::io::ErrorKind::TimedOut => "timeout",
+                        std::io::ErrorKind::InvalidData => "invalid",
+                        _ => "error",
+                    },
+                );
                 log::debug!(
                     "COMMAND ({}) read error from {}: {:?}",
                     self.tunnel_id,
@@ -143,14 +263,71 @@ impl Connection {
 
         match command {
             types::Command::Open(ticket) => {
+                // Client certificate, already verified on the handshake, is required if configured
+                let client_subject = stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(|certs| certs.first())
+                    .and_then(tls::client_auth::subject);
+                if !self.config.ssl_client_ca.is_empty() && client_subject.is_none() {
+                    self.stats.add_failure("tls", "no_client_certificate");
+                    log::error!(
+                        event = "forbidden", tunnel_id = self.tunnel_id, src = src_ip;
+                        "OPEN ({}) from {} without client certificate", self.tunnel_id, src_ip
+                    );
+                    stream
+                        .write_all(types::Response::ForbiddenError.to_bytes())
+                        .await
+                        .unwrap_or_default();
+                    stream.shutdown().await.unwrap_or_default();
+                    return Ok(());
+                }
+
+                // Every OPEN is a request to the broker, so it also consumes a token
+                if !self.limiter.allow(src_addr.ip()) {
+                    self.stats.add_failure("limit", "rate");
+                    log::warn!(
+                        event = "limited", tunnel_id = self.tunnel_id, src = src_ip, reason = "rate";
+                        "OPEN ({}) from {} rejected: rate", self.tunnel_id, src_ip
+                    );
+                    stream
+                        .write_all(types::Response::ForbiddenError.to_bytes())
+                        .await
+                        .unwrap_or_default();
+                    stream.shutdown().await.unwrap_or_default();
+                    return Ok(());
+                }
+
                 let mut relay = relay::RelayConnection::new(
                     self.tunnel_id.clone(),
                     ticket,
-                    self.udsapi.clone(),
+                    udsapi,
                     self.stats.clone(),
-                );
+                    self.sessions.clone(),
+                )
+                .with_src(src_addr)
+                .with_audit(self.audit.clone())
+                .with_client_subject(client_subject)
+                .with_timeouts(self.config.idle_timeout, self.config.max_session_duration)
+                .with_bandwidth(
+                    self.bandwidth.clone(),
+                    self.config.session_bandwidth_upload,
+                    self.config.session_bandwidth_download,
+                )
+                .with_ktls(self.config.ssl_ktls)
+                .with_connect(self.config.ipv6, self.config.connect_timeout);
                 let relay_stop_event = self.stop_event.clone();
                 if let Err(e) = relay.run(stream, relay_stop_event.clone()).await {
+                    if e.downcast_ref::<error::UDSError>().is_some()
+                        && self.limiter.add_ticket_failure(src_addr.ip())
+                    {
+                        self.stats.add_failure("limit", "ban");
+                        log::warn!(
+                            event = "ban", tunnel_id = self.tunnel_id, src = src_ip;
+                            "BAN ({}) of {}, too many invalid tickets", self.tunnel_id, src_ip
+                        );
+                    }
                     log::error!(
                         "RELAY ({}) error from {}: {:?}",
                         self.tunnel_id,
@@ -175,34 +352,53 @@ impl Connection {
                     .context("Error shutting down stream")?;
                 Ok(())
             }
-            // Stat and info are only allowed from config.allow sources (list of ips, no networks)
-            types::Command::Stats(secret) => {
-                log::info!("STATS ({}) from {}", self.tunnel_id, src_ip);
-                // Should be of a valid source and secret
-                let ip = stream
-                    .get_ref()
-                    .0
-                    .peer_addr()
-                    .unwrap()
-                    .ip()
-                    .to_string();
-                // Ip does not have brackets, if it's an IPv6
-                if !self.config.allow.is_empty()
-                    && (!self.config.allow.contains(&ip) || secret != self.config.secret)
+            // Stat and info are only allowed from config.allow sources (list of networks), with the secret
+            types::Command::Stats(ref secret) | types::Command::Info(ref secret) => {
+                let detailed = matches!(command, types::Command::Info(_));
+                log::info!(
+                    "{} ({}) from {}",
+                    if detailed { "INFO" } else { "STATS" },
+                    self.tunnel_id,
+                    src_ip
+                );
+                // Should be of a valid source (if restricted) and always with a valid secret
+                let denied = if !self.config.allow.is_empty()
+                    && !self.config.allow.contains(src_addr.ip())
                 {
+                    Some("source not allowed")
+                } else if !secret_matches(secret, &self.config.secret) {
+                    Some("invalid secret")
+                } else {
+                    None
+                };
+                if let Some(reason) = denied {
+                    self.stats.add_failure("command", "forbidden");
+                    log::error!(
+                        event = "forbidden", tunnel_id = self.tunnel_id, src = src_ip, reason = reason;
+                        "{} ({}) forbidden from {}: {}",
+                        if detailed { "INFO" } else { "STATS" },
+                        self.tunnel_id,
+                        src_ip,
+                        reason
+                    );
                     stream
                         .write_all(types::Response::ForbiddenError.to_bytes())
                         .await
                         .context("Error writing forbidden response")?;
+                    stream.shutdown().await.unwrap_or_default();
+                    return Ok(());
                 }
 
-                let stats = format!(
-                    "{};{};{};{}",
-                    self.stats.get_concurrent_connections(),
-                    self.stats.get_globals_connections(),
-                    self.stats.get_sent_bytes(),
-                    self.stats.get_recv_bytes()
-                );
+                // Global stats, and, if detailed, one line per running relay
+                let stats = stats::StatsReport {
+                    global: self.stats.info(),
+                    sessions: if detailed {
+                        self.sessions.info()
+                    } else {
+                        Vec::new()
+                    },
+                }
+                .to_string();
                 stream
                     .write_all(stats.as_bytes())
                     .await
@@ -215,6 +411,7 @@ impl Connection {
                 Ok(())
             }
             types::Command::Unknown => {
+                self.stats.add_failure("command", "unknown");
                 log_error(None, &buf, &self.tunnel_id, &src_ip, "COMMAND").await;
                 stream
                     .write_all(types::Response::CommandError.to_bytes())
@@ -226,31 +423,140 @@ impl Connection {
             }
         }
     }
+
+    /// Address of the client. If PROXY protocol is enabled and the peer is a trusted proxy,
+    /// the one on the header. On error, returns the failure reason
+    async fn client_address(
+        &self,
+        stream: &mut TcpStream,
+        peer_addr: SocketAddr,
+        deadline: Instant,
+    ) -> Result<SocketAddr, &'static str> {
+        if self.config.proxy_protocol == ProxyProtocol::Off {
+            return Ok(peer_addr);
+        }
+        let required = self.config.proxy_protocol == ProxyProtocol::Require;
+        // An empty list trusts nobody
+        if !self.config.proxy_trusted.contains(peer_addr.ip()) {
+            // Headers from untrusted peers are not parsed, so they will fail the handshake
+            return if required {
+                Err("untrusted")
+            } else {
+                Ok(peer_addr)
+            };
+        }
+
+        // Handshake never starts like a header, so a peek is enough to know if there is one
+        let mut first = [0u8; 1];
+        let has_header = match timeout_at(deadline, stream.peek(&mut first)).await {
+            Ok(Ok(1)) => proxy_protocol::is_header_start(first[0]),
+            Ok(_) => return Err("error"),
+            // Nothing sent, if not required, let the handshake report the timeout
+            Err(_) if !required => false,
+            Err(_) => return Err("timeout"),
+        };
+        if !has_header {
+            return if required {
+                Err("missing")
+            } else {
+                Ok(peer_addr)
+            };
+        }
+
+        match timeout_at(deadline, proxy_protocol::read_header(stream)).await {
+            // No address on header (i.e. health checks), the proxy itself is the client
+            Ok(Ok(src_addr)) => Ok(src_addr.unwrap_or(peer_addr)),
+            Ok(Err(_)) => Err("invalid"),
+            Err(_) => Err("timeout"),
+        }
+    }
 }
 
 impl TunnelServer {
     pub fn new(config: &config::Config, stats: Arc<stats::Stats>) -> Self {
         let config = config.clone();
+        let udsapi = udsapi::HttpUDSApiProvider::new(&config);
+        let virtual_hosts = config
+            .virtual_hosts
+            .iter()
+            .map(|virtual_host| {
+                let provider: Arc<dyn udsapi::UDSApiProvider> =
+                    Arc::new(udsapi::HttpUDSApiProvider {
+                        server: virtual_host.uds_server.clone(),
+                        token: virtual_host.uds_token.clone(),
+                        ..udsapi.clone()
+                    });
+                (virtual_host.clone(), provider)
+            })
+            .collect();
         TunnelServer {
-            udsapi: Arc::new(udsapi::HttpUDSApiProvider::new(&config)),
+            udsapi: Arc::new(udsapi),
+            limiter: Arc::new(limiter::Limiter::new(&config)),
+            bandwidth: Arc::new(throttle::BandwidthLimits::new(&config)),
+            virtual_hosts: Arc::new(virtual_hosts),
+            audit: config
+                .audit_file
+                .as_ref()
+                .map(|path| Arc::new(audit::AuditLog::new(path))),
             config,
             stats,
+            sessions: Arc::new(sessions::SessionRegistry::new()),
+            listener: None,
+            cert_resolvers: Vec::new(),
         }
     }
 
     pub fn with_provider(self, provider: Arc<dyn udsapi::UDSApiProvider>) -> Self {
         TunnelServer {
             udsapi: provider,
-            config: self.config,
-            stats: self.stats,
+            ..self
         }
     }
 
-    pub async fn run(self, stop_event: event::Event) -> Result<()> {
-        let certs = CertificateDer::from_pem_file(self.config.ssl_certificate.clone()).unwrap();
-        let private_key: PrivateKeyDer<'_> =
-            PrivateKeyDer::from_pem_file(self.config.ssl_certificate_key.clone()).unwrap();
+    /// Replaces the broker of the virtual host `name`
+    pub fn with_virtual_host_provider(
+        self,
+        name: &str,
+        provider: Arc<dyn udsapi::UDSApiProvider>,
+    ) -> Self {
+        let virtual_hosts = self
+            .virtual_hosts
+            .iter()
+            .map(|(virtual_host, udsapi)| {
+                if virtual_host.name == name {
+                    (virtual_host.clone(), provider.clone())
+                } else {
+                    (virtual_host.clone(), udsapi.clone())
+                }
+            })
+            .collect();
+        TunnelServer {
+            virtual_hosts: Arc::new(virtual_hosts),
+            ..self
+        }
+    }
+
+    /// Shares the session registry with other servers (i.e. one per runtime)
+    pub fn with_sessions(self, sessions: Arc<sessions::SessionRegistry>) -> Self {
+        TunnelServer { sessions, ..self }
+    }
 
+    /// Shares the rate limits and bans with other servers (i.e. one per runtime)
+    pub fn with_limiter(self, limiter: Arc<limiter::Limiter>) -> Self {
+        TunnelServer { limiter, ..self }
+    }
+
+    /// Shares the global bandwidth limits with other servers (i.e. one per runtime)
+    pub fn with_bandwidth(self, bandwidth: Arc<throttle::BandwidthLimits>) -> Self {
+        TunnelServer { bandwidth, ..self }
+    }
+
+    /// Loads the certificate and binds the listener, so it can be done before dropping privileges.
+    /// If not called, `run` will do it
+    pub async fn listen(&mut self) -> Result<()> {
+        if self.listener.is_some() {
+            return Ok(());
+        }
         let protocol_versions: Vec<&rustls::SupportedProtocolVersion> =
             match self.config.ssl_min_tls_version.as_str() {
                 "1.2" => vec![&TLS12, &TLS13],
@@ -258,13 +564,43 @@ impl TunnelServer {
                 _ => vec![&TLS12, &TLS13],
             };
 
-        let server_tls_config = ServerConfig::builder_with_provider(Arc::new(
-            tls::crypto_provider::provider(&self.config.ssl_ciphers),
-        ))
-        .with_protocol_versions(&protocol_versions)
-        .unwrap()
-        .with_no_client_auth()
-        .with_single_cert(vec![certs], private_key)?;
+        let provider = Arc::new(tls::crypto_provider::provider(&self.config.ssl_ciphers));
+        // Certificates come from resolvers, so they can be replaced while running (SIGHUP or file change)
+        let mut sni_resolver =
+            tls::sni_resolver::SniResolver::new(Arc::new(tls::cert_resolver::CertResolver::new(
+                &self.config.ssl_certificate,
+                &self.config.ssl_certificate_key,
+                &self.config.ssl_password,
+                provider.clone(),
+            )?));
+        for virtual_host in &self.config.virtual_hosts {
+            let cert_resolver = tls::cert_resolver::CertResolver::new(
+                &virtual_host.ssl_certificate,
+                &virtual_host.ssl_certificate_key,
+                &virtual_host.ssl_password,
+                provider.clone(),
+            )
+            .with_context(|| format!("Virtual host [{}]", virtual_host.name))?;
+            sni_resolver =
+                sni_resolver.with_virtual_host(virtual_host.clone(), Arc::new(cert_resolver));
+        }
+        let cert_resolvers = sni_resolver.cert_resolvers();
+
+        let server_tls_config = ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&protocol_versions)
+            .context("Invalid TLS protocol versions for the configured ciphers")?;
+        let mut server_tls_config = if self.config.ssl_client_ca.is_empty() {
+            server_tls_config.with_no_client_auth()
+        } else {
+            server_tls_config.with_client_cert_verifier(tls::client_auth::verifier(
+                &self.config.ssl_client_ca,
+                &self.config.ssl_client_crl,
+                provider,
+            )?)
+        }
+        .with_cert_resolver(Arc::new(sni_resolver));
+        // Needed to hand the keys to the kernel
+        server_tls_config.enable_secret_extraction = self.config.ssl_ktls;
 
         log::debug!(
             "cipher_suites: {:?}",
@@ -287,9 +623,56 @@ impl TunnelServer {
 
         log::info!("Tunnel server running on {}", address);
 
-        let listener = TcpListener::bind(address).await?;
+        let listener = if self.config.runtime_per_core {
+            // Every runtime has its own listener on the same address, the kernel balances between them
+            bind_reuse_port(&address).await?
+        } else {
+            TcpListener::bind(address).await?
+        };
+        self.listener = Some((listener, tls_acceptor));
+        self.cert_resolvers = cert_resolvers;
+        Ok(())
+    }
+
+    /// Certificate resolvers (default first, then virtual hosts), available once `listen` has been called
+    pub fn cert_resolvers(&self) -> Vec<Arc<tls::cert_resolver::CertResolver>> {
+        self.cert_resolvers.clone()
+    }
+
+    pub async fn run(mut self, stop_event: event::Event) -> Result<()> {
+        self.listen().await?;
+        let (listener, tls_acceptor) = self.listener.take().context("Listener not bound")?;
+
+        // Reload the certificates when renewed (i.e. by certbot)
+        for cert_resolver in self.cert_resolvers() {
+            let watch_stopper = stop_event.clone();
+            tokio::spawn(async move {
+                let check_interval = Duration::from_secs(consts::CERT_CHECK_INTERVAL_SECS);
+                loop {
+                    tokio::select! {
+                        _ = watch_stopper.clone() => break,
+                        _ = tokio::time::sleep(check_interval) => {}
+                    }
+                    if let Err(e) = cert_resolver.reload_if_changed() {
+                        log::error!("Error reloading certificate, keeping current one: {:?}", e);
+                    }
+                }
+            });
+        }
 
         loop {
+            // Over max_connections, accept is paused (new ones wait on the backlog) until one ends
+            if self.limiter.is_full() {
+                self.stats.add_failure("limit", "max_connections");
+                log::warn!("Maximum connections reached, pausing accept");
+            }
+            let slot = tokio::select! {
+                _ = stop_event.clone() => {
+                    break;
+                }
+                slot = self.limiter.connection_slot() => slot,
+            };
+
             let stream;
             let check_stop_event = stop_event.clone();
             tokio::select! {
@@ -301,19 +684,17 @@ impl TunnelServer {
                 }
             };
 
-
             let mut connection = Connection::new(
                 tls_acceptor.clone(),
                 stream,
                 uuid::Uuid::new_v4().to_string()[..13].to_string(),
-                self.config.clone(),
-                self.udsapi.clone(),
-                self.stats.clone(),
+                &self,
                 stop_event.clone(),
             );
 
             // Process the connection in a new task
             tokio::spawn(async move {
+                let _slot = slot; // Released when the connection ends
                 if let Err(e) = connection.process().await {
                     log::error!("Connection error: {:?}", e);
                 }
@@ -358,7 +739,10 @@ impl TunnelServer {
         let buf = &buf[..*size];
 
         if let Ok(command) = types::Command::from_bytes(buf) {
-            log::info!("COMMAND ({tunnel_id}) {command} from {src}");
+            log::info!(
+                event = "command", tunnel_id = tunnel_id, src = src, command:% = command;
+                "COMMAND ({tunnel_id}) {command} from {src}"
+            );
             Ok(command)
         } else {
             let hex = to_hex(buf);
@@ -379,6 +763,28 @@ impl TunnelServer {
 }
 
 // Some helper functions
+async fn bind_reuse_port(address: &str) -> Result<TcpListener> {
+    let addr = tokio::net::lookup_host(address)
+        .await?
+        .next()
+        .with_context(|| format!("Invalid listen address {}", address))?;
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    Ok(socket.listen(1024)?)
+}
+
+// Constant time comparison, so the secret can not be guessed timing the responses
+fn secret_matches(received: &str, expected: &str) -> bool {
+    received.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
 fn to_hex(bytes: &[u8]) -> String {
     bytes
         .iter()
@@ -398,12 +804,21 @@ async fn log_error(
     // Returns true if it was a timeout
     if let Some(e) = result {
         if e.kind() == std::io::ErrorKind::TimedOut {
-            log::error!("{head} ({connection_id}) error from {from}: timed out");
+            log::error!(
+                event = "error", stage = head, tunnel_id = connection_id, src = from;
+                "{head} ({connection_id}) error from {from}: timed out"
+            );
         } else {
-            log::error!("{head} ({connection_id}) error from {from}: {e}");
+            log::error!(
+                event = "error", stage = head, tunnel_id = connection_id, src = from;
+                "{head} ({connection_id}) error from {from}: {e}"
+            );
         }
     } else {
         let hex = to_hex(buf);
-        log::error!("{head} ({connection_id}) invalid from {from}: {hex}");
+        log::error!(
+            event = "invalid", stage = head, tunnel_id = connection_id, src = from;
+            "{head} ({connection_id}) invalid from {from}: {hex}"
+        );
     }
 }
@@ -0,0 +1,237 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{event, stats};
+
+/// Number of ticket characters exposed on session info (never the full ticket)
+pub const TICKET_PREFIX_LENGTH: usize = 8;
+
+/// A running relay, as seen from the outside
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub tunnel_id: String,
+    pub src: String,
+    pub dst: String,
+    pub ticket: String, // Only the prefix of the ticket
+    pub start: SystemTime,
+    pub stats: Arc<stats::Stats>, // Local stats of the relay
+    pub notify_ticket: String,    // UDS notify ticket, can also be used to find the session
+    pub stopper: event::Event,    // Setting it stops the relay
+}
+
+impl Session {
+    pub fn new(
+        tunnel_id: &str,
+        src: &str,
+        dst: &str,
+        ticket: &str,
+        stats: Arc<stats::Stats>,
+    ) -> Self {
+        Session {
+            tunnel_id: tunnel_id.to_string(),
+            src: src.to_string(),
+            dst: dst.to_string(),
+            ticket: ticket.chars().take(TICKET_PREFIX_LENGTH).collect(),
+            start: SystemTime::now(),
+            stats,
+            notify_ticket: String::new(),
+            stopper: event::Event::new(),
+        }
+    }
+
+    pub fn with_notify_ticket(mut self, notify_ticket: &str) -> Self {
+        self.notify_ticket = notify_ticket.to_string();
+        self
+    }
+
+    pub fn with_stopper(mut self, stopper: event::Event) -> Self {
+        self.stopper = stopper;
+        self
+    }
+
+    pub fn info(&self) -> stats::SessionInfo {
+        stats::SessionInfo {
+            tunnel_id: self.tunnel_id.clone(),
+            src: self.src.clone(),
+            dst: self.dst.clone(),
+            ticket: self.ticket.clone(),
+            start: self
+                .start
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            sent_bytes: self.stats.get_sent_bytes(),
+            recv_bytes: self.stats.get_recv_bytes(),
+        }
+    }
+}
+
+/// Registry of the running relays of a tunnel server, keyed by tunnel id
+#[derive(Debug, Default)]
+pub struct SessionRegistry {
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        SessionRegistry {
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn register(&self, session: Session) {
+        if let Ok(mut sessions) = self.sessions.lock() {
+            sessions.insert(session.tunnel_id.clone(), session);
+        } else {
+            log::error!("Error locking the sessions registry");
+        }
+    }
+
+    pub fn unregister(&self, tunnel_id: &str) -> Option<Session> {
+        match self.sessions.lock() {
+            Ok(mut sessions) => sessions.remove(tunnel_id),
+            Err(_) => {
+                log::error!("Error locking the sessions registry");
+                None
+            }
+        }
+    }
+
+    pub fn get(&self, tunnel_id: &str) -> Option<Session> {
+        self.sessions
+            .lock()
+            .ok()
+            .and_then(|sessions| sessions.get(tunnel_id).cloned())
+    }
+
+    /// Stops the relay of the session with the given tunnel id or notify ticket.
+    /// The relay itself will unregister and notify the end to UDS as on a normal close
+    pub fn terminate(&self, id_or_notify: &str) -> Option<stats::SessionInfo> {
+        let session = self.sessions.lock().ok().and_then(|sessions| {
+            sessions
+                .values()
+                .find(|s| {
+                    s.tunnel_id == id_or_notify
+                        || (!s.notify_ticket.is_empty() && s.notify_ticket == id_or_notify)
+                })
+                .cloned()
+        })?;
+        log::info!(
+            "TERMINATE ({}) {} to {} requested",
+            session.tunnel_id,
+            session.src,
+            session.dst
+        );
+        session.stopper.set().ok()?;
+        Some(session.info())
+    }
+
+    /// Info of all running sessions, oldest first
+    pub fn info(&self) -> Vec<stats::SessionInfo> {
+        let mut sessions = match self.sessions.lock() {
+            Ok(sessions) => sessions.values().map(Session::info).collect::<Vec<_>>(),
+            Err(_) => {
+                log::error!("Error locking the sessions registry");
+                Vec::new()
+            }
+        };
+        sessions.sort_by(|a, b| a.start.cmp(&b.start).then(a.tunnel_id.cmp(&b.tunnel_id)));
+        sessions
+    }
+
+    /// Running relays to the destination `dst` ("host:port")
+    pub fn count_by_dst(&self, dst: &str) -> usize {
+        self.sessions
+            .lock()
+            .map(|sessions| sessions.values().filter(|s| s.dst == dst).count())
+            .unwrap_or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().map(|s| s.len()).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_register_unregister() {
+        let registry = SessionRegistry::new();
+        assert!(registry.is_empty());
+
+        let stats = Arc::new(stats::Stats::new());
+        stats.add_send_bytes(10);
+        stats.add_recv_bytes(20);
+        registry.register(Session::new(
+            "tunnel-1",
+            "127.0.0.1:1234",
+            "10.0.0.1:3389",
+            "abcdefghijklmnopqrstuvwxyz",
+            stats.clone(),
+        ));
+        registry.register(Session::new(
+            "tunnel-2",
+            "[::1]:1234",
+            "10.0.0.2:3389",
+            "0123456789",
+            Arc::new(stats::Stats::new()),
+        ));
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.count_by_dst("10.0.0.1:3389"), 1);
+        assert_eq!(registry.count_by_dst("10.0.0.3:3389"), 0);
+
+        let info = registry.get("tunnel-1").unwrap().info();
+        assert_eq!(info.ticket, "abcdefgh");
+        assert_eq!(info.sent_bytes, 10);
+        assert_eq!(info.recv_bytes, 20);
+
+        // Local stats are shared with the relay
+        stats.add_send_bytes(5);
+        assert_eq!(registry.get("tunnel-1").unwrap().info().sent_bytes, 15);
+
+        assert!(registry.terminate("unknown").is_none());
+
+        assert!(registry.unregister("tunnel-1").is_some());
+        assert!(registry.unregister("tunnel-1").is_none());
+        let info = registry.info();
+        assert_eq!(info.len(), 1);
+        assert_eq!(info[0].tunnel_id, "tunnel-2");
+    }
+
+    #[tokio::test]
+    async fn test_registry_terminate() {
+        let registry = SessionRegistry::new();
+        let stopper = event::Event::new();
+        registry.register(
+            Session::new(
+                "tunnel-1",
+                "127.0.0.1:1234",
+                "10.0.0.1:3389",
+                "abcdefghijklmnopqrstuvwxyz",
+                Arc::new(stats::Stats::new()),
+            )
+            .with_notify_ticket("notify-1")
+            .with_stopper(stopper.clone()),
+        );
+
+        // By notify ticket
+        let info = registry.terminate("notify-1").unwrap();
+        assert_eq!(info.tunnel_id, "tunnel-1");
+        tokio::time::timeout(std::time::Duration::from_millis(100), stopper.clone())
+            .await
+            .unwrap();
+        // Termination does not unregister, the relay does
+        assert_eq!(registry.len(), 1);
+        // By tunnel id
+        assert!(registry.terminate("tunnel-1").is_some());
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+};
+
+use tokio::{
+    io::Interest,
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+};
+
+/// Kernel pipe to move data from one socket to another with splice, without copying it to
+/// userspace. Used by the relays on kernel TLS, where the client socket carries plaintext
+#[derive(Debug)]
+pub struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+    pending: usize, // Bytes on the pipe, not yet moved to the destination
+}
+
+impl Pipe {
+    pub fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        // SAFETY: fds has room for both ends
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: just created, nobody else owns them
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        Ok(Pipe {
+            read,
+            write,
+            pending: 0,
+        })
+    }
+
+    /// Waits for data on `reader`, and moves up to `max` bytes of it to the pipe.
+    /// The pipe must be drained before. 0 means end of stream
+    pub async fn fill(&mut self, reader: &OwnedReadHalf, max: usize) -> io::Result<usize> {
+        debug_assert_eq!(self.pending, 0);
+        let socket: &TcpStream = reader.as_ref();
+        loop {
+            socket.readable().await?;
+            // The pipe is empty, so WouldBlock can only come from the socket
+            match socket.try_io(Interest::READABLE, || {
+                splice(socket.as_raw_fd(), self.write.as_raw_fd(), max)
+            }) {
+                Ok(n) => {
+                    self.pending = n;
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Moves all the data on the pipe to `writer`
+    pub async fn drain(&mut self, writer: &OwnedWriteHalf) -> io::Result<()> {
+        let socket: &TcpStream = writer.as_ref();
+        while self.pending > 0 {
+            socket.writable().await?;
+            match socket.try_io(Interest::WRITABLE, || {
+                splice(self.read.as_raw_fd(), socket.as_raw_fd(), self.pending)
+            }) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.pending -= n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    // SAFETY: both are open descriptors, and null offsets are allowed for sockets and pipes
+    let n = unsafe {
+        libc::splice(
+            from,
+            ptr::null_mut(),
+            to,
+            ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connect = TcpStream::connect(listener.local_addr().unwrap());
+        let (accepted, connected) = tokio::join!(listener.accept(), connect);
+        (accepted.unwrap().0, connected.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_splice_between_sockets() {
+        let (mut source, relay_in) = socket_pair().await;
+        let (relay_out, mut destination) = socket_pair().await;
+        let (reader, _) = relay_in.into_split();
+        let (_, writer) = relay_out.into_split();
+
+        let data: Vec<u8> = (0..300_000).map(|i| i as u8).collect();
+        let sent = data.clone();
+        let sender = tokio::spawn(async move {
+            source.write_all(&sent).await.unwrap();
+            source.shutdown().await.unwrap();
+        });
+        let receiver = tokio::spawn(async move {
+            let mut received = Vec::new();
+            destination.read_to_end(&mut received).await.unwrap();
+            received
+        });
+
+        let mut pipe = Pipe::new().unwrap();
+        let mut total = 0;
+        loop {
+            let n = pipe.fill(&reader, 64 * 1024).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            assert!(n <= 64 * 1024);
+            pipe.drain(&writer).await.unwrap();
+            total += n;
+        }
+        drop(writer);
+        sender.await.unwrap();
+        assert_eq!(total, data.len());
+        assert_eq!(receiver.await.unwrap(), data);
+    }
+}
@@ -1,4 +1,73 @@
-use std::sync::atomic::AtomicU64;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    str::FromStr,
+    sync::{atomic::AtomicU64, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+// Buckets (in seconds) for the UDS api requests latency
+pub const UDS_LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+// Buckets (in seconds) for the relays duration, from 1 second to 1 day
+pub const RELAY_DURATION_BUCKETS: &[f64] = &[
+    1.0, 10.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 14400.0, 28800.0, 86400.0,
+];
+
+/// Cumulative histogram, prometheus style (each bucket counts observations <= bound)
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        for (bound, bucket) in self.bounds.iter().zip(self.buckets.iter()) {
+            if secs <= *bound {
+                bucket.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+        }
+        self.sum_micros.fetch_add(
+            value.as_micros() as u64,
+            std::sync::atomic::Ordering::Relaxed,
+        );
+        self.count
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Returns the (upper bound, cumulative count) of each bucket. +Inf bucket is `get_count`
+    pub fn get_buckets(&self) -> Vec<(f64, u64)> {
+        self.bounds
+            .iter()
+            .zip(self.buckets.iter())
+            .map(|(bound, bucket)| (*bound, bucket.load(std::sync::atomic::Ordering::Relaxed)))
+            .collect()
+    }
+
+    pub fn get_sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(std::sync::atomic::Ordering::Relaxed))
+    }
+
+    pub fn get_count(&self) -> u64 {
+        self.count.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
 
 #[derive(Debug)]
 pub struct Stats {
@@ -7,6 +76,13 @@ pub struct Stats {
     start_time: std::time::Instant,
     total_connections: AtomicU64,
     concurrent_connections: AtomicU64,
+    // Time relays waited due to bandwidth limits, in microseconds
+    throttled_send_micros: AtomicU64,
+    throttled_recv_micros: AtomicU64,
+    // Failures, keyed by (stage, reason), i.e. ("handshake", "timeout")
+    failures: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    uds_latency: Histogram,
+    relay_duration: Histogram,
 }
 
 impl Stats {
@@ -17,6 +93,11 @@ impl Stats {
             start_time: std::time::Instant::now(),
             total_connections: AtomicU64::new(0),
             concurrent_connections: AtomicU64::new(0),
+            throttled_send_micros: AtomicU64::new(0),
+            throttled_recv_micros: AtomicU64::new(0),
+            failures: Mutex::new(BTreeMap::new()),
+            uds_latency: Histogram::new(UDS_LATENCY_BUCKETS),
+            relay_duration: Histogram::new(RELAY_DURATION_BUCKETS),
         }
     }
 
@@ -66,6 +147,74 @@ impl Stats {
         self.concurrent_connections
             .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
     }
+
+    /// Time spent waiting to send to the client, due to bandwidth limits
+    pub fn get_throttled_send(&self) -> Duration {
+        Duration::from_micros(
+            self.throttled_send_micros
+                .load(std::sync::atomic::Ordering::Relaxed),
+        )
+    }
+
+    pub fn add_throttled_send(&self, time: Duration) {
+        self.throttled_send_micros.fetch_add(
+            time.as_micros() as u64,
+            std::sync::atomic::Ordering::Relaxed,
+        );
+    }
+
+    /// Time spent waiting to receive from the client, due to bandwidth limits
+    pub fn get_throttled_recv(&self) -> Duration {
+        Duration::from_micros(
+            self.throttled_recv_micros
+                .load(std::sync::atomic::Ordering::Relaxed),
+        )
+    }
+
+    pub fn add_throttled_recv(&self, time: Duration) {
+        self.throttled_recv_micros.fetch_add(
+            time.as_micros() as u64,
+            std::sync::atomic::Ordering::Relaxed,
+        );
+    }
+
+    pub fn add_failure(&self, stage: &'static str, reason: &'static str) {
+        if let Ok(mut failures) = self.failures.lock() {
+            *failures.entry((stage, reason)).or_default() += 1;
+        }
+    }
+
+    pub fn get_failures(&self) -> Vec<((&'static str, &'static str), u64)> {
+        self.failures
+            .lock()
+            .map(|failures| failures.iter().map(|(k, v)| (*k, *v)).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn get_failure(&self, stage: &str, reason: &str) -> u64 {
+        self.failures
+            .lock()
+            .ok()
+            .and_then(|failures| failures.get(&(stage, reason)).copied())
+            .unwrap_or_default()
+    }
+
+    pub fn uds_latency(&self) -> &Histogram {
+        &self.uds_latency
+    }
+
+    pub fn relay_duration(&self) -> &Histogram {
+        &self.relay_duration
+    }
+
+    pub fn info(&self) -> GlobalStatsInfo {
+        GlobalStatsInfo {
+            concurrent_connections: self.get_concurrent_connections(),
+            total_connections: self.get_globals_connections(),
+            sent_bytes: self.get_sent_bytes(),
+            recv_bytes: self.get_recv_bytes(),
+        }
+    }
 }
 
 impl Default for Stats {
@@ -73,3 +222,185 @@ impl Default for Stats {
         Self::new()
     }
 }
+
+/// Snapshot of the global counters, as sent on the wire by the `STAT` command
+/// (`concurrent;total;sent;recv`)
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GlobalStatsInfo {
+    pub concurrent_connections: u64,
+    pub total_connections: u64,
+    pub sent_bytes: u64,
+    pub recv_bytes: u64,
+}
+
+impl FromStr for GlobalStatsInfo {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s
+            .trim()
+            .split(';')
+            .map(|f| f.parse::<u64>())
+            .collect::<Result<Vec<u64>, _>>()
+            .map_err(|_| "Invalid stats value")?;
+        if fields.len() != 4 {
+            return Err("Invalid stats line");
+        }
+        Ok(GlobalStatsInfo {
+            concurrent_connections: fields[0],
+            total_connections: fields[1],
+            sent_bytes: fields[2],
+            recv_bytes: fields[3],
+        })
+    }
+}
+
+impl fmt::Display for GlobalStatsInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{};{};{};{}",
+            self.concurrent_connections, self.total_connections, self.sent_bytes, self.recv_bytes
+        )
+    }
+}
+
+/// Information about a single running relay, as sent on the wire by the `INFO` command
+/// (`tunnel_id;src;dst;ticket;start;sent;recv`, start is the unix timestamp of the relay start)
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub tunnel_id: String,
+    pub src: String,
+    pub dst: String,
+    pub ticket: String,
+    pub start: u64,
+    pub sent_bytes: u64,
+    pub recv_bytes: u64,
+}
+
+impl FromStr for SessionInfo {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.trim().split(';').collect::<Vec<&str>>();
+        if fields.len() != 7 {
+            return Err("Invalid session line");
+        }
+        let number = |v: &str| v.parse::<u64>().map_err(|_| "Invalid session value");
+        Ok(SessionInfo {
+            tunnel_id: fields[0].to_string(),
+            src: fields[1].to_string(),
+            dst: fields[2].to_string(),
+            ticket: fields[3].to_string(),
+            start: number(fields[4])?,
+            sent_bytes: number(fields[5])?,
+            recv_bytes: number(fields[6])?,
+        })
+    }
+}
+
+impl fmt::Display for SessionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{};{};{};{};{};{};{}",
+            self.tunnel_id,
+            self.src,
+            self.dst,
+            self.ticket,
+            self.start,
+            self.sent_bytes,
+            self.recv_bytes
+        )
+    }
+}
+
+/// Full response of a `STAT` or `INFO` command.
+/// First line is always the global stats, and, for `INFO`, one line per running relay follows
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatsReport {
+    pub global: GlobalStatsInfo,
+    pub sessions: Vec<SessionInfo>,
+}
+
+impl FromStr for StatsReport {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.lines().filter(|l| !l.trim().is_empty());
+        let global = lines.next().ok_or("Empty stats response")?.parse()?;
+        let sessions = lines
+            .map(SessionInfo::from_str)
+            .collect::<Result<Vec<SessionInfo>, _>>()?;
+        Ok(StatsReport { global, sessions })
+    }
+}
+
+impl fmt::Display for StatsReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.global)?;
+        for session in &self.sessions {
+            write!(f, "\n{}", session)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_is_cumulative() {
+        let histogram = Histogram::new(&[0.1, 1.0, 10.0]);
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_millis(500));
+        histogram.observe(Duration::from_secs(20));
+        assert_eq!(histogram.get_buckets(), vec![(0.1, 1), (1.0, 2), (10.0, 2)]);
+        assert_eq!(histogram.get_count(), 3);
+        assert_eq!(histogram.get_sum(), Duration::from_millis(20550));
+    }
+
+    #[test]
+    fn test_failures() {
+        let stats = Stats::new();
+        stats.add_failure("handshake", "timeout");
+        stats.add_failure("handshake", "timeout");
+        stats.add_failure("command", "invalid");
+        assert_eq!(stats.get_failure("handshake", "timeout"), 2);
+        assert_eq!(stats.get_failure("command", "invalid"), 1);
+        assert_eq!(stats.get_failure("command", "timeout"), 0);
+        assert_eq!(stats.get_failures().len(), 2);
+    }
+
+    #[test]
+    fn test_global_stats_info_roundtrip() {
+        let info = GlobalStatsInfo {
+            concurrent_connections: 749,
+            total_connections: 947,
+            sent_bytes: 96432,
+            recv_bytes: 13804,
+        };
+        assert_eq!(info.to_string(), "749;947;96432;13804");
+        assert_eq!("749;947;96432;13804\n".parse::<GlobalStatsInfo>(), Ok(info));
+        assert!("749;947;96432".parse::<GlobalStatsInfo>().is_err());
+        assert!("749;947;x;13804".parse::<GlobalStatsInfo>().is_err());
+    }
+
+    #[test]
+    fn test_stats_report_from_str() {
+        let report = "1;2;3;4\nabcdef-0123;[::1]:3456;10.0.0.1:3389;abcdefgh;1700000000;10;20\n"
+            .parse::<StatsReport>()
+            .unwrap();
+        assert_eq!(report.global.concurrent_connections, 1);
+        assert_eq!(report.global.recv_bytes, 4);
+        assert_eq!(report.sessions.len(), 1);
+        assert_eq!(report.sessions[0].src, "[::1]:3456");
+        assert_eq!(report.sessions[0].dst, "10.0.0.1:3389");
+        assert_eq!(report.sessions[0].recv_bytes, 20);
+        assert_eq!(report.to_string().parse::<StatsReport>().unwrap(), report);
+
+        assert!("".parse::<StatsReport>().is_err());
+        assert!("1;2;3;4\ninvalid".parse::<StatsReport>().is_err());
+    }
+}
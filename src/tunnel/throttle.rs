@@ -0,0 +1,190 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::config;
+
+/// Token bucket of bytes per second. Consuming can leave it in debt, so a whole
+/// read buffer is always relayed, and the next read waits until the debt is paid
+#[derive(Debug)]
+pub struct Bandwidth {
+    rate: f64, // Bytes per second
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64, // Up to one second of rate, negative if in debt
+    updated: Instant,
+}
+
+impl Bandwidth {
+    /// Bucket of `rate` bytes per second. None if rate is 0 (no limit)
+    pub fn new(rate: u64) -> Option<Arc<Self>> {
+        (rate > 0).then(|| {
+            Arc::new(Bandwidth {
+                rate: rate as f64,
+                state: Mutex::new(BucketState {
+                    tokens: rate as f64,
+                    updated: Instant::now(),
+                }),
+            })
+        })
+    }
+
+    fn consume_at(&self, bytes: usize, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, now);
+        state.tokens -= bytes as f64;
+    }
+
+    // Time until the debt (if any) is paid
+    fn wait_time_at(&self, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, now);
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.rate)
+        }
+    }
+
+    fn refill(&self, state: &mut BucketState, now: Instant) {
+        if now > state.updated {
+            let elapsed = now.duration_since(state.updated).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * self.rate).min(self.rate);
+            state.updated = now;
+        }
+    }
+}
+
+/// Throttle of one direction of a relay, made of all the buckets that apply to it
+/// (i.e. the global one, and the one of the session)
+#[derive(Debug, Default, Clone)]
+pub struct Throttle {
+    buckets: Vec<Arc<Bandwidth>>,
+}
+
+impl Throttle {
+    pub fn new(buckets: impl IntoIterator<Item = Option<Arc<Bandwidth>>>) -> Self {
+        Throttle {
+            buckets: buckets.into_iter().flatten().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// Waits until every bucket is out of debt, calling `on_wait` before each wait with its
+    /// duration. Returns the time spent waiting
+    pub async fn ready(&self, mut on_wait: impl FnMut(Duration)) -> Duration {
+        let mut waited = Duration::ZERO;
+        loop {
+            let wait = self.wait_time_at(Instant::now());
+            if wait.is_zero() {
+                return waited;
+            }
+            on_wait(wait);
+            tokio::time::sleep(wait).await;
+            waited += wait;
+        }
+    }
+
+    /// Takes `bytes` from every bucket
+    pub fn consume(&self, bytes: usize) {
+        let now = Instant::now();
+        for bucket in &self.buckets {
+            bucket.consume_at(bytes, now);
+        }
+    }
+
+    fn wait_time_at(&self, now: Instant) -> Duration {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.wait_time_at(now))
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// Global bandwidth limits, shared by all the relays of the process.
+/// Upload is client to server, download is server to client
+#[derive(Debug, Default)]
+pub struct BandwidthLimits {
+    pub upload: Option<Arc<Bandwidth>>,
+    pub download: Option<Arc<Bandwidth>>,
+}
+
+impl BandwidthLimits {
+    pub fn new(config: &config::Config) -> Self {
+        BandwidthLimits {
+            upload: Bandwidth::new(config.bandwidth_upload),
+            download: Bandwidth::new(config.bandwidth_download),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bandwidth_debt() {
+        let bucket = Bandwidth::new(1000).unwrap();
+        let now = Instant::now();
+        // One second of burst
+        bucket.consume_at(1000, now);
+        assert_eq!(bucket.wait_time_at(now), Duration::ZERO);
+        // In debt, must wait until paid
+        bucket.consume_at(500, now);
+        assert_eq!(bucket.wait_time_at(now), Duration::from_millis(500));
+        assert_eq!(
+            bucket.wait_time_at(now + Duration::from_millis(200)),
+            Duration::from_millis(300)
+        );
+        assert_eq!(
+            bucket.wait_time_at(now + Duration::from_millis(500)),
+            Duration::ZERO
+        );
+        // Never more than one second of burst
+        let later = now + Duration::from_secs(60);
+        bucket.consume_at(1500, later);
+        assert_eq!(bucket.wait_time_at(later), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_no_limit() {
+        assert!(Bandwidth::new(0).is_none());
+        let throttle = Throttle::new([None, None]);
+        assert!(throttle.is_empty());
+        throttle.consume(1_000_000);
+        assert_eq!(throttle.wait_time_at(Instant::now()), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_throttle_waits_for_slowest() {
+        let global = Bandwidth::new(10000);
+        let session = Bandwidth::new(1000);
+        let throttle = Throttle::new([global.clone(), session]);
+        let now = Instant::now();
+        throttle.consume(2000);
+        assert_eq!(throttle.wait_time_at(now), Duration::from_secs(1));
+        // Global bucket is shared with other throttles
+        let other = Throttle::new([global]);
+        assert_eq!(other.wait_time_at(now), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_ready() {
+        let throttle = Throttle::new([Bandwidth::new(10000)]);
+        assert_eq!(throttle.ready(|_| panic!("No wait")).await, Duration::ZERO);
+        throttle.consume(11000);
+        let mut announced = Duration::ZERO;
+        let waited = throttle.ready(|wait| announced += wait).await;
+        assert_eq!(waited, announced);
+        assert!(waited >= Duration::from_millis(90), "{:?}", waited);
+        assert!(waited < Duration::from_millis(150), "{:?}", waited);
+    }
+}
@@ -8,6 +8,7 @@ pub enum Command {
     Open(String),
     Test,
     Stats(String),
+    Info(String),
     Unknown,
 }
 
@@ -46,6 +47,9 @@ impl FromStr for Command {
                 if secret.len() == consts::SECRET_LENGTH {
                     // Should match "^[a-zA-Z0-9]{32}$", 32 characters long and only ascii alphanumeric
                     if secret.chars().all(|c| c.is_ascii_alphanumeric()) {
+                        if &s[..consts::COMMAND_LENGTH] == consts::COMMAND_INFO {
+                            return Ok(Command::Info(secret.to_string()));
+                        }
                         return Ok(Command::Stats(secret.to_string()));
                     }
                     Err("Invalid secret, not alphanumeric")
@@ -91,6 +95,7 @@ impl std::fmt::Display for Command {
             Command::Open(ticket) => write!(f, "OPEN {}", ticket),
             Command::Test => write!(f, "TEST"),
             Command::Stats(secret) => write!(f, "STAT {}", secret),
+            Command::Info(secret) => write!(f, "INFO {}", secret),
             Command::Unknown => write!(f, "UNKNOWN"),
         }
     }
@@ -103,6 +108,7 @@ pub enum Response {
     HandshakeError,
     ForbiddenError,
     ConnectError,
+    BusyError, // Too many connections
     Ok,
 }
 
@@ -115,6 +121,7 @@ impl Response {
             Response::HandshakeError => consts::RESPONSE_ERROR_HANDSHAKE,
             Response::ForbiddenError => consts::RESPONSE_FORBIDDEN,
             Response::ConnectError => consts::RESPONSE_ERROR_CONNECT,
+            Response::BusyError => consts::RESPONSE_ERROR_BUSY,
             Response::Ok => consts::RESPONSE_OK,
         }
     }
@@ -177,6 +184,21 @@ mod tests {
             ),
             Err("Invalid secret length")
         );
+        // Info uses same secret as stat
+        assert_eq!(
+            Command::from_str(
+                "INFO1234567890123456789012345678901234567890123456789012345678901234"
+            ),
+            Ok(Command::Info(
+                "1234567890123456789012345678901234567890123456789012345678901234".to_string()
+            ))
+        );
+        assert_eq!(
+            Command::from_str(
+                "INFO123456789012345678901234567890123456789012345678901234567890123"
+            ),
+            Err("Invalid secret length")
+        );
         assert_eq!(Command::from_str("INVALID"), Ok(Command::Unknown));
 
         // Test into
@@ -205,6 +227,7 @@ mod tests {
             Response::ForbiddenError.to_string(),
             consts::RESPONSE_FORBIDDEN
         );
+        assert_eq!(Response::BusyError.to_string(), consts::RESPONSE_ERROR_BUSY);
         assert_eq!(Response::Ok.to_string(), consts::RESPONSE_OK);
 
         // Test into
@@ -1,20 +1,86 @@
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 
-use reqwest::ClientBuilder;
+use reqwest::{ClientBuilder, StatusCode};
 use anyhow::Result;
 
-use super::{config, consts};
+use super::{config, consts, error::UDSError};
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct UdsTicketResponse {
+    // Single destination. Not needed if the broker sends a list of destinations
+    #[serde(default)]
     pub host: String,
+    #[serde(default)]
     pub port: u16,
     pub notify: String,
+    // Destinations to try instead of host/port (i.e. clustered services), and how to choose them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub destinations: Vec<Destination>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balance: Option<Balance>,
+    // Overrides of the configured relay limits for this ticket, in seconds (0 means no limit)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_session_duration: Option<u64>,
+    // Overrides of the configured per session bandwidth limits, in bytes per second (0 means no limit)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bandwidth_upload: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bandwidth_download: Option<u64>,
+}
+
+impl UdsTicketResponse {
+    /// Destinations of the relay, the single host/port if the broker sent no list
+    pub fn destinations(&self) -> Vec<Destination> {
+        if self.destinations.is_empty() {
+            vec![Destination {
+                host: self.host.clone(),
+                port: self.port,
+                weight: None,
+            }]
+        } else {
+            self.destinations.clone()
+        }
+    }
+}
+
+/// Destination server of a relay
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Destination {
+    pub host: String,
+    pub port: u16,
+    // Relative weight, for the weighted balance (1 if not set, 0 means only if the rest fail)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+}
+
+impl Destination {
+    /// "host:port", as shown on logs and sessions
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+/// Order in which the destinations are tried, until one connects
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    /// Randomly, proportional to the weights
+    Weighted,
+    /// Fewest running relays first
+    LeastConnections,
+    /// As sent by the broker. Also used for unknown values
+    #[default]
+    #[serde(other)]
+    Ordered,
 }
 
 #[async_trait]
 pub trait UDSApiProvider: Send + Sync {
+    /// On error, an `UDSError` means that the broker answered, but rejected the ticket (not found or invalid).
+    /// Any other failure (broker unreachable or failing, uds_token refused, ...) is a plain error
     async fn request(
         &self,
         ticket: &str,
@@ -22,20 +88,29 @@ pub trait UDSApiProvider: Send + Sync {
         query_params: Option<&str>,
     ) -> Result<UdsTicketResponse>;
 
+    /// Requests the ticket. If the client presented a certificate, its subject is sent too
     async fn get_ticket(
         &self,
         ticket: &str,
         ip: &str,
+        client_subject: Option<&str>,
     ) -> Result<UdsTicketResponse> {
-        self.request(ticket, ip, None).await
+        let query = client_subject.map(|subject| {
+            form_urlencoded::Serializer::new(String::new())
+                .append_pair("subject", subject)
+                .finish()
+        });
+        self.request(ticket, ip, query.as_deref()).await
     }
 
+    /// Notifies the end of the relay, with why it was closed (i.e. "client_closed", "idle_timeout")
     async fn notify_end(
         &self,
         ticket: &str,
         sent: u64,
         recv: u64,
         duration: std::time::Duration,
+        reason: &str,
     ) -> Result<UdsTicketResponse> {
         // Ignore response
         let _ = self
@@ -43,16 +118,19 @@ pub trait UDSApiProvider: Send + Sync {
                 ticket,
                 "stop",
                 Some(
-                    format!("sent={}&recv={}&elapsed={}", sent, recv, duration.as_secs()).as_str(),
+                    format!(
+                        "sent={}&recv={}&elapsed={}&reason={}",
+                        sent,
+                        recv,
+                        duration.as_secs(),
+                        reason
+                    )
+                    .as_str(),
                 ),
             )
             .await;
         // Return empty response
-        Ok(UdsTicketResponse {
-            host: String::new(),
-            port: 0,
-            notify: String::new(),
-        })
+        Ok(UdsTicketResponse::default())
     }
 }
 
@@ -131,7 +209,15 @@ impl UDSApiProvider for HttpUDSApiProvider {
             return Ok(uds_response);
         } else {
             log::error!("UDS Response status error: {:?}", response);
-            return Err(anyhow::Error::msg(format!("UDS Response status error: {:?}", response)));
+            let message = format!("UDS Response status error: {}", response.status());
+            // Only these mean that the broker rejected the ticket. Server errors or a
+            // refused uds_token are not the client fault, so not reported as UDSError
+            return match response.status() {
+                StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND | StatusCode::GONE => {
+                    Err(UDSError::new(&message).into())
+                }
+                _ => Err(anyhow::Error::msg(message)),
+            };
         }
     }
 }
@@ -1,7 +1,19 @@
 use std::time::Duration;
-use tokio::{net::TcpStream, time::timeout};
+use tokio::{io::AsyncWriteExt, net::TcpStream, time::timeout};
 use tokio_rustls::client::TlsStream;
-use udstunnel::tunnel::client;
+use udstunnel::{
+    tls::{callbacks::TLSClientCallback, client::ConnectionBuilder},
+    tunnel::{client, consts},
+};
+
+struct HandshakeCallback;
+
+#[async_trait::async_trait]
+impl TLSClientCallback for HandshakeCallback {
+    async fn process(&self, stream: &mut TcpStream) -> tokio::io::Result<()> {
+        stream.write_all(consts::HANDSHAKE_V1).await
+    }
+}
 
 #[allow(dead_code)]
 pub async fn open_client_no_handshake(port: u16) -> TcpStream {
@@ -25,3 +37,51 @@ pub async fn open_client_no_handshake(port: u16) -> TcpStream {
 pub async fn open_client_with_handshake(port: u16) -> TlsStream<TcpStream> {
     client::connect("localhost", port, false).await.unwrap()
 }
+
+#[allow(dead_code)]
+pub async fn open_client_with_certificate(
+    port: u16,
+    cert_path: &str,
+    key_path: &str,
+) -> std::io::Result<TlsStream<TcpStream>> {
+    ConnectionBuilder::new("localhost", port)
+        .with_verify_ssl(false)
+        .with_client_certificate(cert_path, key_path)
+        .with_connect_callback(HandshakeCallback)
+        .connect()
+        .await
+}
+
+#[allow(dead_code)]
+pub async fn open_client_with_server_name(port: u16, server_name: &str) -> TlsStream<TcpStream> {
+    ConnectionBuilder::new("localhost", port)
+        .with_verify_ssl(false)
+        .with_server_name(server_name)
+        .with_connect_callback(HandshakeCallback)
+        .connect()
+        .await
+        .unwrap()
+}
+
+// Sends a PROXY protocol header before the handshake, as a load balancer would
+struct ProxyHeaderCallback(Vec<u8>);
+
+#[async_trait::async_trait]
+impl TLSClientCallback for ProxyHeaderCallback {
+    async fn process(&self, stream: &mut TcpStream) -> tokio::io::Result<()> {
+        stream.write_all(&self.0).await?;
+        stream.write_all(consts::HANDSHAKE_V1).await
+    }
+}
+
+#[allow(dead_code)]
+pub async fn open_client_with_proxy_header(
+    port: u16,
+    header: &[u8],
+) -> std::io::Result<TlsStream<TcpStream>> {
+    ConnectionBuilder::new("localhost", port)
+        .with_verify_ssl(false)
+        .with_connect_callback(ProxyHeaderCallback(header.to_vec()))
+        .connect()
+        .await
+}
@@ -12,7 +12,15 @@ pub async fn read() -> config::Config {
         .unwrap();
 
     config.listen_port = utils::find_free_port(Some(&config.listen_address));
+    // Tests that need the audit log set their own file
+    config.audit_file = None;
 
-    tunnel::log::setup(&None, &config.loglevel);
+    tunnel::log::setup(
+        &None,
+        &config.loglevel,
+        config.logsize,
+        config.lognumber,
+        config.log_format,
+    );
     config
 }
@@ -1,13 +1,19 @@
 extern crate udstunnel;
 
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 use log;
 use tokio::{self, task::JoinHandle};
 
 use anyhow::Result;
 
-use udstunnel::tunnel::{config, event, server, stats, udsapi};
+use udstunnel::{
+    tls::cert_resolver::CertResolver,
+    tunnel::{config, error, event, server, sessions, stats, udsapi},
+};
 
 use super::remote::Remote;
 
@@ -54,10 +60,52 @@ impl udsapi::UDSApiProvider for UDSApiProviderMock {
             query_params.unwrap_or("")
         );
 
+        // Tickets starting with "invalid" are rejected, as the broker would do
+        if ticket.starts_with("invalid") {
+            return Err(error::UDSError::new("Invalid ticket").into());
+        }
+
+        // Tickets starting with "idle" have their own (short) idle timeout
+        let idle_timeout = ticket.starts_with("idle").then_some(1);
+        // And the ones starting with "slow", their own upload bandwidth (64 KiB/s)
+        let bandwidth_upload = ticket.starts_with("slow").then_some(64 * 1024);
+
+        // Tickets starting with "unreachable" point to a server that never answers (TEST-NET-1)
+        let host = if ticket.starts_with("unreachable") {
+            "192.0.2.1"
+        } else {
+            "[::1]"
+        };
+        // And the ones starting with "failover" list a server that is down before the good one
+        let destinations = if ticket.starts_with("failover") {
+            [1, self.port]
+                .map(|port| udsapi::Destination {
+                    host: "[::1]".to_string(),
+                    port,
+                    weight: None,
+                })
+                .to_vec()
+        } else if ticket.starts_with("blackhole") {
+            // Two servers that never answer (TEST-NET-1 again)
+            ["192.0.2.1", "192.0.2.2"]
+                .map(|host| udsapi::Destination {
+                    host: host.to_string(),
+                    port: self.port,
+                    weight: None,
+                })
+                .to_vec()
+        } else {
+            Vec::new()
+        };
+
         Ok(udsapi::UdsTicketResponse {
-            host: "[::1]".to_string(),
+            host: host.to_string(),
             port: self.port,
             notify: "notify_012345678901234567890123456789012".to_string(),
+            idle_timeout,
+            bandwidth_upload,
+            destinations,
+            ..Default::default()
         })
     }
 }
@@ -69,6 +117,10 @@ pub struct TunnelServer {
     pub remote_handle: JoinHandle<()>,
     pub stopper: event::Event,
     pub stats: Arc<stats::Stats>,
+    pub sessions: Arc<sessions::SessionRegistry>,
+    pub cert_resolver: Arc<CertResolver>,
+    // Requests received by the broker of every virtual host
+    pub virtual_host_requests: HashMap<String, Arc<Mutex<Vec<Request>>>>,
 }
 
 #[allow(dead_code)]
@@ -93,12 +145,20 @@ impl TunnelServer {
         let stopper = event::Event::new();
         let task_stopper = stopper.clone();
         let stats = Arc::new(stats::Stats::new());
-        let stats_co = stats.clone();
-        let server_handle = tokio::spawn(async move {
-            let mut tunnel = server::TunnelServer::new(&launch_config, stats_co.clone());
-            if mock_remotes {
-                tunnel = tunnel.with_provider(task_provider);
+        let mut tunnel = server::TunnelServer::new(&launch_config, stats.clone());
+        let mut virtual_host_requests = HashMap::new();
+        if mock_remotes {
+            tunnel = tunnel.with_provider(task_provider);
+            for virtual_host in &launch_config.virtual_hosts {
+                let mock = UDSApiProviderMock::new(remote.port);
+                virtual_host_requests.insert(virtual_host.name.clone(), mock.req.clone());
+                tunnel = tunnel.with_virtual_host_provider(&virtual_host.name, Arc::new(mock));
             }
+        }
+        let sessions = tunnel.sessions.clone();
+        tunnel.listen().await.unwrap();
+        let cert_resolver = tunnel.cert_resolvers()[0].clone();
+        let server_handle = tokio::spawn(async move {
             let result = tunnel.run(task_stopper).await;
             if let Err(e) = result {
                 log::error!("Error: {:?}", e);
@@ -116,6 +176,9 @@ impl TunnelServer {
             server_handle,
             stopper,
             stats,
+            sessions,
+            cert_resolver,
+            virtual_host_requests,
         }
     }
 
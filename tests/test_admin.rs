@@ -0,0 +1,160 @@
+#[cfg(test)]
+extern crate udstunnel;
+
+mod fake;
+
+use std::time::Duration;
+
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+use udstunnel::tunnel::{admin, consts, event};
+
+async fn request(port: u16, method: &str, path: &str) -> String {
+    let mut stream = TcpStream::connect(format!("127.0.0.1:{}", port))
+        .await
+        .unwrap();
+    stream
+        .write_all(format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path).as_bytes())
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_admin_terminate_session() {
+    let mut config = fake::config::read().await;
+    let admin_port = fake::utils::find_free_port(Some("127.0.0.1"));
+    config.admin_listen = format!("127.0.0.1:{}", admin_port);
+
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+    let reqs = server.requests.clone().unwrap();
+
+    let admin_stopper = event::Event::new();
+    let admin = admin::AdminServer::new(&config, server.sessions.clone());
+    let task_stopper = admin_stopper.clone();
+    let admin_handle = tokio::spawn(async move {
+        admin.run(task_stopper).await.unwrap();
+    });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // Open a relay
+    let mut client = fake::client::open_client_with_handshake(config.listen_port).await;
+    let ticket = [b'x'; consts::TICKET_LENGTH];
+    let command = format!(
+        "{}{}",
+        consts::COMMAND_OPEN,
+        std::str::from_utf8(&ticket).unwrap()
+    );
+    client.write_all(command.as_bytes()).await.unwrap();
+    let mut buffer = [0; 128];
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_OK.as_bytes());
+
+    let response = request(admin_port, "GET", "/sessions").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    let tunnel_id = server.sessions.info()[0].tunnel_id.clone();
+    assert!(response.contains(&tunnel_id));
+
+    let response = request(admin_port, "DELETE", &format!("/sessions/{}", tunnel_id)).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+    // Client connection gets closed, and the relay is gone
+    let closed = timeout(Duration::from_secs(2), client.read(&mut buffer))
+        .await
+        .unwrap();
+    assert!(matches!(closed, Ok(0) | Err(_)));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(server.sessions.is_empty());
+
+    // UDS got notified of the end, as on a normal close
+    {
+        let reqs = reqs.lock().unwrap();
+        assert_eq!(reqs.len(), 2);
+        assert_eq!(reqs[1].message, "stop");
+        assert!(reqs[1]
+            .query_params
+            .as_deref()
+            .unwrap()
+            .ends_with("&reason=terminated"));
+    }
+
+    let response = request(admin_port, "DELETE", &format!("/sessions/{}", tunnel_id)).await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    admin_stopper.set().unwrap();
+    admin_handle.await.unwrap();
+    server.abort();
+}
+
+#[tokio::test]
+async fn test_admin_rejects_non_loopback() {
+    let mut config = fake::config::read().await;
+    config.admin_listen = "0.0.0.0:4480".to_string();
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    let admin = admin::AdminServer::new(&config, server.sessions.clone());
+    assert!(admin.run(event::Event::new()).await.is_err());
+
+    server.abort();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_admin_unix_socket() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut config = fake::config::read().await;
+    let path = std::env::temp_dir().join(format!("udstunnel-admin-{}.sock", config.listen_port));
+    config.admin_listen = format!("unix:{}", path.display());
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    let admin_stopper = event::Event::new();
+    let admin = admin::AdminServer::new(&config, server.sessions.clone());
+    let task_stopper = admin_stopper.clone();
+    let admin_handle = tokio::spawn(async move {
+        admin.run(task_stopper).await.unwrap();
+    });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    stream
+        .write_all(b"GET /sessions HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("[]"));
+
+    admin_stopper.set().unwrap();
+    admin_handle.await.unwrap();
+    assert!(!path.exists());
+    server.abort();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_admin_unix_socket_never_removes_other_files() {
+    let mut config = fake::config::read().await;
+    let path = std::env::temp_dir().join(format!("udstunnel-admin-{}.file", config.listen_port));
+    std::fs::write(&path, "not a socket").unwrap();
+    config.admin_listen = format!("unix:{}", path.display());
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    let admin = admin::AdminServer::new(&config, server.sessions.clone());
+    assert!(admin.run(event::Event::new()).await.is_err());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+
+    std::fs::remove_file(path).unwrap();
+    server.abort();
+}
@@ -0,0 +1,84 @@
+#[cfg(test)]
+extern crate udstunnel;
+
+mod fake;
+
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use udstunnel::tunnel::consts;
+
+const DATA_LENGTH: usize = 192 * 1024;
+
+// Relays DATA_LENGTH bytes through the echo remote, returning how long it took
+async fn echo_through_relay(port: u16, ticket_prefix: &str) -> Duration {
+    let mut client = fake::client::open_client_with_handshake(port).await;
+    let command = format!(
+        "{}{}{}",
+        consts::COMMAND_OPEN,
+        ticket_prefix,
+        "x".repeat(consts::TICKET_LENGTH - ticket_prefix.len())
+    );
+    client.write_all(command.as_bytes()).await.unwrap();
+    let mut buffer = [0; 128];
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_OK.as_bytes());
+
+    let start = Instant::now();
+    let (mut reader, mut writer) = tokio::io::split(client);
+    let writer_task = tokio::spawn(async move {
+        writer.write_all(&[b'x'; DATA_LENGTH]).await.unwrap();
+        writer
+    });
+    let mut received = vec![0; DATA_LENGTH];
+    reader.read_exact(&mut received).await.unwrap();
+    let elapsed = start.elapsed();
+    writer_task.await.unwrap();
+    elapsed
+}
+
+#[tokio::test]
+async fn test_bandwidth_from_ticket() {
+    let config = fake::config::read().await;
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    // 64 KiB/s for the ticket, with one second of burst
+    let elapsed = echo_through_relay(config.listen_port, "slow").await;
+    assert!(elapsed >= Duration::from_millis(800), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(4), "{:?}", elapsed);
+
+    assert!(server.stats.get_throttled_recv() >= Duration::from_millis(800));
+    assert_eq!(server.stats.get_throttled_send(), Duration::ZERO);
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_bandwidth_unlimited() {
+    let mut config = fake::config::read().await;
+    config.session_bandwidth_upload = 0;
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    let elapsed = echo_through_relay(config.listen_port, "").await;
+    assert!(elapsed < Duration::from_millis(800), "{:?}", elapsed);
+    assert_eq!(server.stats.get_throttled_recv(), Duration::ZERO);
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_throttled_session_is_not_idle() {
+    let mut config = fake::config::read().await;
+    // Shorter than the waits for the bandwidth of the ticket
+    config.idle_timeout = Duration::from_millis(100);
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    let elapsed = echo_through_relay(config.listen_port, "slow").await;
+    assert!(elapsed >= Duration::from_millis(800), "{:?}", elapsed);
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
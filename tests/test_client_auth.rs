@@ -0,0 +1,90 @@
+extern crate udstunnel;
+
+mod fake;
+
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+
+use udstunnel::tunnel::{config, consts};
+
+fn open_command() -> String {
+    format!("{}{}", consts::COMMAND_OPEN, "x".repeat(consts::TICKET_LENGTH))
+}
+
+async fn client_auth_config() -> config::Config {
+    let mut config = fake::config::read().await;
+    config.ssl_client_ca = "tests/certs/ca.pem".to_string();
+    config.ssl_client_crl = "tests/certs/crl.pem".to_string();
+    config
+}
+
+#[tokio::test]
+async fn test_open_requires_client_certificate() {
+    let config = client_auth_config().await;
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+    let mut buffer = [0; 128];
+
+    // Without certificate, only OPEN is forbidden
+    let mut client = fake::client::open_client_with_handshake(config.listen_port).await;
+    client.write_all(consts::COMMAND_TEST.as_bytes()).await.unwrap();
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_OK.as_bytes());
+
+    let mut client = fake::client::open_client_with_handshake(config.listen_port).await;
+    client.write_all(open_command().as_bytes()).await.unwrap();
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_FORBIDDEN.as_bytes());
+    assert_eq!(server.stats.get_failure("tls", "no_client_certificate"), 1);
+    assert!(server.requests.as_ref().unwrap().lock().unwrap().is_empty());
+
+    // With a valid certificate, the subject is sent to the broker
+    let mut client = fake::client::open_client_with_certificate(
+        config.listen_port,
+        "tests/certs/client.pem",
+        "tests/certs/client_key.pem",
+    )
+    .await
+    .unwrap();
+    client.write_all(open_command().as_bytes()).await.unwrap();
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_OK.as_bytes());
+    client.shutdown().await.unwrap();
+
+    assert_eq!(
+        server.requests.as_ref().unwrap().lock().unwrap()[0].query_params,
+        Some("subject=C%3DES%2C+O%3DMe%2C+CN%3Dclient".to_string())
+    );
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_revoked_client_certificate() {
+    let config = client_auth_config().await;
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    // On TLS 1.3 the client may finish the handshake before the server rejects the certificate
+    if let Ok(mut client) = fake::client::open_client_with_certificate(
+        config.listen_port,
+        "tests/certs/revoked_client.pem",
+        "tests/certs/revoked_client_key.pem",
+    )
+    .await
+    {
+        client.write_all(open_command().as_bytes()).await.ok();
+        let mut buffer = [0; 128];
+        let read = timeout(Duration::from_secs(2), client.read(&mut buffer))
+            .await
+            .unwrap();
+        assert!(matches!(read, Err(_) | Ok(0)));
+    }
+    assert!(server.requests.as_ref().unwrap().lock().unwrap().is_empty());
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
@@ -1,4 +1,8 @@
-use udstunnel::tunnel::config::ConfigLoader;
+use udstunnel::tunnel::{
+    config::{ConfigLoader, NetworkList, VirtualHost},
+    log::LogFormat,
+    proxy_protocol::ProxyProtocol,
+};
 mod fake;
 
 #[cfg(test)]
@@ -22,24 +26,50 @@ mod tests {
         assert_eq!(config.logfile, None);
         assert_eq!(config.logsize, 10 * 1024 * 1024);
         assert_eq!(config.lognumber, 4);
+        assert_eq!(config.log_format, LogFormat::Text);
+        assert_eq!(config.audit_file, None);
         assert_eq!(config.listen_address, "0.0.0.0");
         assert_eq!(config.listen_port, 4443);
         assert!(!config.ipv6);
+        assert_eq!(config.proxy_protocol, ProxyProtocol::Off);
+        assert!(config.proxy_trusted.is_empty());
         assert!(config.workers > 0);
+        assert!(!config.runtime_per_core);
         assert_eq!(config.ssl_min_tls_version, "1.2");
         assert_eq!(config.ssl_certificate, "/etc/certs/server.pem");
         assert_eq!(config.ssl_certificate_key, "/etc/certs/key.pem");
-        //assert_eq!(config.ssl_password, "");
+        assert!(config.ssl_password.is_empty());
         assert_eq!(config.ssl_ciphers, "");
+        assert_eq!(config.ssl_client_ca, "");
+        assert_eq!(config.ssl_client_crl, "");
+        assert!(!config.ssl_ktls);
         assert_eq!(config.uds_server, "");
         assert_eq!(config.uds_token, "");
         assert_eq!(config.uds_timeout, Duration::from_millis(10000));
         assert!(config.uds_verify_ssl);
         assert_eq!(config.command_timeout, Duration::from_millis(3000));
         assert_eq!(config.handshake_timeout, Duration::from_millis(3000));
+        assert_eq!(config.connect_timeout, Duration::from_millis(5000));
+        assert_eq!(config.idle_timeout, Duration::ZERO);
+        assert_eq!(config.max_session_duration, Duration::ZERO);
+        assert_eq!(config.bandwidth_upload, 0);
+        assert_eq!(config.bandwidth_download, 0);
+        assert_eq!(config.session_bandwidth_upload, 0);
+        assert_eq!(config.session_bandwidth_download, 0);
         // Sha256 of empty string
         assert_eq!(config.secret, "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
-        assert_eq!(config.allow, Vec::<String>::new());
+        assert!(config.allow.is_empty());
+        assert_eq!(config.rate_limit, 0.0);
+        assert_eq!(config.rate_limit_burst, 20);
+        assert_eq!(config.ban_failures, 0);
+        assert_eq!(config.ban_window, Duration::from_secs(60));
+        assert_eq!(config.ban_time, Duration::from_secs(600));
+        assert_eq!(config.max_connections, 0);
+        assert_eq!(config.max_connections_per_ip, 0);
+        assert_eq!(config.metrics_address, "127.0.0.1");
+        assert_eq!(config.metrics_port, 0);
+        assert_eq!(config.admin_listen, "");
+        assert!(config.virtual_hosts.is_empty());
     }
 
     #[test]
@@ -55,27 +85,74 @@ mod tests {
         assert_eq!(config.logfile, Some("/tmp/tunnel.log".to_string()));
         assert_eq!(config.logsize, 120 * 1024 * 1024);
         assert_eq!(config.lognumber, 3);
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.audit_file, Some("/tmp/udstunnel-audit.log".to_string()));
         assert_eq!(config.listen_address, "[::]");
         assert_eq!(config.listen_port, 7777);
         assert!(config.ipv6);
-        assert!(config.workers > 0);
+        assert_eq!(config.proxy_protocol, ProxyProtocol::Accept);
+        assert_eq!(
+            config.proxy_trusted,
+            NetworkList::parse("", "127.0.0.1, ::1").unwrap()
+        );
+        assert_eq!(config.workers, 2);
+        assert!(!config.runtime_per_core);
         assert_eq!(config.ssl_min_tls_version, "1.3");
         assert_eq!(config.ssl_certificate, "tests/certs/cert.pem");
         assert_eq!(config.ssl_certificate_key, "tests/certs/key.pem");
-        //assert_eq!(config.ssl_password, "MyPassword");
+        assert_eq!(config.ssl_password.as_str(), "MyPassword");
+        // Never shown on debug output
+        assert!(!format!("{:?}", config).contains("MyPassword"));
         assert_eq!(
             config.ssl_ciphers,
             "TLS_AES_256_GCM_SHA384:TLS_CHACHA20_POLY1305_SHA256:ECDHE-ECDSA-CHACHA20-POLY1305-SHA256"
         );
+        assert!(config.ssl_ktls);
         assert_eq!(config.uds_server, "http://127.0.0.1:8000/uds/rest/tunnel/ticket");
         assert_eq!(config.uds_token, "uds_token");
         assert_eq!(config.uds_timeout, Duration::from_millis(4000));
         assert!(!config.uds_verify_ssl);
         assert_eq!(config.command_timeout, Duration::from_millis(1000));
         assert_eq!(config.handshake_timeout, Duration::from_millis(1000));
+        assert_eq!(config.connect_timeout, Duration::from_millis(2000));
+        assert_eq!(config.idle_timeout, Duration::from_secs(3600));
+        assert_eq!(config.max_session_duration, Duration::from_secs(86400));
+        assert_eq!(config.bandwidth_upload, 0);
+        assert_eq!(config.bandwidth_download, 100 * 1024 * 1024);
+        assert_eq!(config.session_bandwidth_upload, 1024 * 1024);
+        assert_eq!(config.session_bandwidth_download, 0);
         // Sha256 of "MySecret"
         assert_eq!(config.secret, "49562cfc3b17139ea01c480b9c86a2ddacb38ff1b2e9db1bf66bab7a4e3f1fb5");
-        assert_eq!(config.allow, vec!["127.0.0.1", "127.0.0.2", "::1"]);
+        assert_eq!(
+            config.allow.networks(),
+            ["127.0.0.1/32", "127.0.0.2/32", "::1/128", "10.10.0.0/16"]
+                .map(|network| network.parse().unwrap())
+        );
+        assert_eq!(config.rate_limit, 50.0);
+        assert_eq!(config.rate_limit_burst, 100);
+        assert_eq!(config.ban_failures, 5);
+        assert_eq!(config.ban_window, Duration::from_secs(30));
+        assert_eq!(config.ban_time, Duration::from_secs(300));
+        assert_eq!(config.max_connections, 10000);
+        assert_eq!(config.max_connections_per_ip, 100);
+        assert_eq!(config.metrics_address, "127.0.0.1");
+        assert_eq!(config.metrics_port, 9470);
+        assert_eq!(config.admin_listen, "127.0.0.1:4480");
+        assert_eq!(
+            config.virtual_hosts,
+            vec![VirtualHost {
+                name: "customer1".to_string(),
+                server_names: vec![
+                    "customer1.example.com".to_string(),
+                    "*.customer1.test".to_string()
+                ],
+                ssl_certificate: "tests/certs/cert2.pem".to_string(),
+                ssl_certificate_key: "tests/certs/key2.pem".to_string(),
+                ssl_password: config.ssl_password.clone(),
+                uds_server: "http://127.0.0.1:8001/uds/rest/tunnel/ticket".to_string(),
+                uds_token: "customer1_token".to_string(),
+            }]
+        );
     }
 
     #[test]
@@ -97,4 +174,96 @@ mod tests {
         std::env::remove_var("UDSTUNNEL_PIDFILE");
         std::env::remove_var("UDSTUNNEL_USER");
     }
+
+    #[test]
+    fn test_network_list() {
+        let networks = NetworkList::parse(
+            "allow",
+            "192.168.1.0/24, 10.0.0.1, 2001:db8::/32, ::ffff:172.16.0.0/108",
+        )
+        .unwrap();
+        for ip in [
+            "192.168.1.77",
+            "::ffff:192.168.1.77",
+            "10.0.0.1",
+            "2001:db8:1::5",
+            "172.16.3.4",
+            "::ffff:172.16.3.4",
+        ] {
+            assert!(networks.contains(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["192.168.2.1", "10.0.0.2", "2001:db9::1", "::1", "172.32.0.1"] {
+            assert!(!networks.contains(ip.parse().unwrap()), "{}", ip);
+        }
+        // Host bits are ignored
+        assert_eq!(
+            NetworkList::parse("allow", "10.1.2.3/8").unwrap(),
+            NetworkList::parse("allow", "10.0.0.0/8").unwrap()
+        );
+
+        for invalid in ["10.0.0.0/33", "localhost", "10.0.0", "::1/129"] {
+            let err = NetworkList::parse("allow", invalid).unwrap_err();
+            assert!(err.to_string().contains(invalid));
+        }
+    }
+
+    #[test]
+    fn test_invalid_allow_rejected() {
+        let _lock = CONFIG_LOCK.lock().unwrap();
+        std::env::set_var("UDSTUNNEL_ALLOW", "127.0.0.1, 10.0.0.0/99");
+        let config = ConfigLoader::new()
+            .with_filename("tests/udstunnel.conf")
+            .load();
+        std::env::remove_var("UDSTUNNEL_ALLOW");
+
+        assert!(config.unwrap_err().to_string().contains("10.0.0.0/99"));
+    }
+
+    #[test]
+    fn test_invalid_durations_rejected() {
+        let _lock = CONFIG_LOCK.lock().unwrap();
+        for key in ["ban_time", "ban_window", "idle_timeout", "max_session_duration"] {
+            let var = format!("UDSTUNNEL_{}", key.to_uppercase());
+            for value in ["inf", "1e30"] {
+                std::env::set_var(&var, value);
+                let config = ConfigLoader::new()
+                    .with_filename("tests/udstunnel.conf")
+                    .load();
+                std::env::remove_var(&var);
+
+                assert!(config.unwrap_err().to_string().contains(key));
+            }
+        }
+    }
+
+    #[test]
+    fn test_proxy_protocol_requires_trusted() {
+        let _lock = CONFIG_LOCK.lock().unwrap();
+        std::env::set_var("UDSTUNNEL_PROXY_TRUSTED", "");
+        let config = ConfigLoader::new()
+            .with_filename("tests/udstunnel.conf")
+            .load();
+        std::env::remove_var("UDSTUNNEL_PROXY_TRUSTED");
+
+        assert!(config.unwrap_err().to_string().contains("proxy_trusted"));
+    }
+
+    #[test]
+    fn test_ssl_password_from_file() {
+        let _lock = CONFIG_LOCK.lock().unwrap();
+        let password_file = std::env::temp_dir().join("udstunnel-test-ssl-password");
+        std::fs::write(&password_file, "FromFile\n").unwrap();
+        std::env::set_var(
+            "UDSTUNNEL_SSL_PASSWORD",
+            format!("file:{}", password_file.display()),
+        );
+
+        let config = ConfigLoader::new()
+            .with_filename("tests/udstunnel.conf")
+            .load();
+        std::env::remove_var("UDSTUNNEL_SSL_PASSWORD");
+        std::fs::remove_file(&password_file).unwrap();
+
+        assert_eq!(config.unwrap().ssl_password.as_str(), "FromFile");
+    }
 }
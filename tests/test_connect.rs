@@ -0,0 +1,99 @@
+#[cfg(test)]
+extern crate udstunnel;
+
+mod fake;
+
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use udstunnel::tunnel::consts;
+
+#[tokio::test]
+async fn test_connect_timeout() {
+    let mut config = fake::config::read().await;
+    config.connect_timeout = Duration::from_millis(500);
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    let mut client = fake::client::open_client_with_handshake(config.listen_port).await;
+    let ticket = format!("unreachable{}", "x".repeat(consts::TICKET_LENGTH - 11));
+    client
+        .write_all(format!("{}{}", consts::COMMAND_OPEN, ticket).as_bytes())
+        .await
+        .unwrap();
+
+    // Answered once the timeout expires, not when the OS gives up
+    let start = Instant::now();
+    let mut buffer = [0; 128];
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_ERROR_CONNECT.as_bytes());
+    let elapsed = start.elapsed();
+    assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+
+    // Timeout, or error if the address is not even routable here
+    assert_eq!(
+        server.stats.get_failure("connect", "timeout")
+            + server.stats.get_failure("connect", "error"),
+        1
+    );
+    assert!(server.sessions.is_empty());
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_connect_timeout_for_all_destinations() {
+    let mut config = fake::config::read().await;
+    config.connect_timeout = Duration::from_millis(500);
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    let mut client = fake::client::open_client_with_handshake(config.listen_port).await;
+    let ticket = format!("blackhole{}", "x".repeat(consts::TICKET_LENGTH - 9));
+    client
+        .write_all(format!("{}{}", consts::COMMAND_OPEN, ticket).as_bytes())
+        .await
+        .unwrap();
+
+    // One timeout for both, not one for each
+    let start = Instant::now();
+    let mut buffer = [0; 128];
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_ERROR_CONNECT.as_bytes());
+    let elapsed = start.elapsed();
+    assert!(elapsed < Duration::from_millis(900), "{:?}", elapsed);
+    assert!(server.sessions.is_empty());
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_connect_failover() {
+    let config = fake::config::read().await;
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    let mut client = fake::client::open_client_with_handshake(config.listen_port).await;
+    let ticket = format!("failover{}", "x".repeat(consts::TICKET_LENGTH - 8));
+    client
+        .write_all(format!("{}{}", consts::COMMAND_OPEN, ticket).as_bytes())
+        .await
+        .unwrap();
+    let mut buffer = [0; 128];
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_OK.as_bytes());
+
+    // Relaying to the second destination
+    client.write_all(b"ping").await.unwrap();
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], b"ping");
+    let sessions = server.sessions.info();
+    assert_eq!(sessions.len(), 1);
+    assert!(sessions[0].dst.starts_with("[::1]:"));
+    assert_ne!(sessions[0].dst, "[::1]:1");
+    assert_eq!(server.stats.get_failure("connect", "failover"), 1);
+    assert_eq!(server.stats.get_failure("connect", "error"), 0);
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
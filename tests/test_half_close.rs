@@ -0,0 +1,66 @@
+#[cfg(test)]
+extern crate udstunnel;
+
+mod fake;
+
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use udstunnel::tunnel::consts;
+
+const DATA_LENGTH: usize = 1024 * 1024;
+
+#[tokio::test]
+async fn test_client_half_close() {
+    let config = fake::config::read().await;
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+    let reqs = server.requests.clone().unwrap();
+
+    let mut client = fake::client::open_client_with_handshake(config.listen_port).await;
+    let command = format!(
+        "{}{}",
+        consts::COMMAND_OPEN,
+        "x".repeat(consts::TICKET_LENGTH)
+    );
+    client.write_all(command.as_bytes()).await.unwrap();
+    let mut buffer = [0; 128];
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_OK.as_bytes());
+
+    // The client closes its side as soon as everything is sent, but still expects the reply
+    let (mut reader, mut writer) = tokio::io::split(client);
+    let writer_task = tokio::spawn(async move {
+        writer.write_all(&[b'x'; DATA_LENGTH]).await.unwrap();
+        writer.shutdown().await.unwrap();
+    });
+    let mut received = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), reader.read_to_end(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    writer_task.await.unwrap();
+    // Remote echoes everything, and closes once the relay closes its side
+    assert_eq!(received.len(), DATA_LENGTH);
+
+    let mut stop = None;
+    for _ in 0..20 {
+        stop = reqs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|r| r.message == "stop")
+            .and_then(|r| r.query_params.clone());
+        if stop.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let stop = stop.unwrap();
+    assert!(stop.starts_with(&format!("sent={0}&recv={0}&", DATA_LENGTH)));
+    assert!(stop.ends_with("&reason=client_closed"));
+    assert!(server.sessions.is_empty());
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
@@ -0,0 +1,53 @@
+#[cfg(test)]
+extern crate udstunnel;
+
+mod fake;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use udstunnel::tunnel::consts;
+
+const DATA_LENGTH: usize = 1024 * 1024;
+
+// Echoes DATA_LENGTH bytes through a relay, with kernel TLS enabled or not.
+// Where the kernel has no kTLS, relays fall back to userspace and must behave the same
+async fn echo_with_ktls(ktls: bool) {
+    let mut config = fake::config::read().await;
+    config.ssl_ktls = ktls;
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    let mut client = fake::client::open_client_with_handshake(config.listen_port).await;
+    let command = format!("{}{}", consts::COMMAND_OPEN, "x".repeat(consts::TICKET_LENGTH));
+    client.write_all(command.as_bytes()).await.unwrap();
+    let mut buffer = [0; 128];
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_OK.as_bytes());
+
+    let data: Vec<u8> = (0..DATA_LENGTH).map(|i| (i % 251) as u8).collect();
+    let (mut reader, mut writer) = tokio::io::split(client);
+    let sent = data.clone();
+    let writer_task = tokio::spawn(async move {
+        writer.write_all(&sent).await.unwrap();
+        writer
+    });
+    let mut received = vec![0; DATA_LENGTH];
+    reader.read_exact(&mut received).await.unwrap();
+    assert!(received == data);
+
+    let mut client = reader.unsplit(writer_task.await.unwrap());
+    client.shutdown().await.unwrap();
+
+    assert_eq!(server.stats.get_recv_bytes(), DATA_LENGTH as u64);
+    server.abort();
+    server.server_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_relay_with_ktls() {
+    echo_with_ktls(true).await;
+}
+
+#[tokio::test]
+async fn test_relay_without_ktls() {
+    echo_with_ktls(false).await;
+}
@@ -0,0 +1,199 @@
+extern crate udstunnel;
+
+mod fake;
+
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+
+use udstunnel::tunnel::consts;
+
+async fn command(port: u16, command: &str) -> Vec<u8> {
+    let mut client = fake::client::open_client_with_handshake(port).await;
+    client.write_all(command.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap_or_default();
+    response
+}
+
+fn open_command(ticket_prefix: &str) -> String {
+    format!(
+        "{}{}{}",
+        consts::COMMAND_OPEN,
+        ticket_prefix,
+        "x".repeat(consts::TICKET_LENGTH - ticket_prefix.len())
+    )
+}
+
+#[tokio::test]
+async fn test_ban_after_invalid_tickets() {
+    let mut config = fake::config::read().await;
+    config.ban_failures = 2;
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    for _ in 0..2 {
+        assert_eq!(
+            command(config.listen_port, &open_command("invalid")).await,
+            consts::RESPONSE_ERROR_TICKET.as_bytes()
+        );
+    }
+    assert_eq!(server.stats.get_failure("ticket", "invalid"), 2);
+    assert_eq!(server.stats.get_failure("limit", "ban"), 1);
+
+    // Banned, closed before the handshake
+    let mut client = fake::client::open_client_no_handshake(config.listen_port).await;
+    client
+        .write_all(consts::HANDSHAKE_V1)
+        .await
+        .unwrap_or_default();
+    let mut buffer = [0; 128];
+    assert_eq!(client.read(&mut buffer).await.unwrap_or_default(), 0);
+    assert_eq!(server.stats.get_failure("limit", "banned"), 1);
+    assert_eq!(server.requests.as_ref().unwrap().lock().unwrap().len(), 2);
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_rate_limit() {
+    let mut config = fake::config::read().await;
+    config.rate_limit = 0.001;
+    config.rate_limit_burst = 3;
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    // One token for the connection, another one for the OPEN
+    let mut client = fake::client::open_client_with_handshake(config.listen_port).await;
+    client.write_all(open_command("").as_bytes()).await.unwrap();
+    let mut buffer = [0; 128];
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_OK.as_bytes());
+    client.shutdown().await.unwrap();
+
+    // Last token, enough to connect but not to OPEN
+    assert_eq!(
+        command(config.listen_port, &open_command("")).await,
+        consts::RESPONSE_FORBIDDEN.as_bytes()
+    );
+    // No tokens left
+    let mut client = fake::client::open_client_no_handshake(config.listen_port).await;
+    client
+        .write_all(consts::HANDSHAKE_V1)
+        .await
+        .unwrap_or_default();
+    assert_eq!(client.read(&mut buffer).await.unwrap_or_default(), 0);
+    assert_eq!(server.stats.get_failure("limit", "rate"), 2);
+    // Only the first ticket reached the broker
+    assert_eq!(
+        server.requests.as_ref().unwrap().lock().unwrap()[0].ticket,
+        "x".repeat(consts::TICKET_LENGTH)
+    );
+    assert!(server.requests.as_ref().unwrap().lock().unwrap()[1..]
+        .iter()
+        .all(|request| request.ticket != "x".repeat(consts::TICKET_LENGTH)));
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_max_connections_per_ip() {
+    let mut config = fake::config::read().await;
+    config.max_connections_per_ip = 1;
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    // Sends the handshake, so the source is known without waiting for a PROXY header
+    let first = fake::client::open_client_with_handshake(config.listen_port).await;
+    let mut second = fake::client::open_client_no_handshake(config.listen_port).await;
+    second.write_all(consts::HANDSHAKE_V1).await.unwrap();
+    let mut response = Vec::new();
+    second.read_to_end(&mut response).await.unwrap_or_default();
+    assert_eq!(response, consts::RESPONSE_ERROR_BUSY.as_bytes());
+    assert_eq!(
+        server.stats.get_failure("limit", "max_connections_per_ip"),
+        1
+    );
+
+    // Once closed, the slot is free again
+    drop(first);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(
+        command(config.listen_port, consts::COMMAND_TEST).await,
+        consts::RESPONSE_OK.as_bytes()
+    );
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_max_connections_pauses_accept() {
+    let mut config = fake::config::read().await;
+    config.max_connections = 1;
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    let first = fake::client::open_client_no_handshake(config.listen_port).await;
+    // Waits on the backlog until the first one is closed
+    let port = config.listen_port;
+    let mut second = tokio::spawn(async move { command(port, consts::COMMAND_TEST).await });
+    assert!(timeout(Duration::from_millis(300), &mut second)
+        .await
+        .is_err());
+    assert_eq!(server.stats.get_failure("limit", "max_connections"), 1);
+
+    drop(first);
+    assert_eq!(
+        timeout(Duration::from_secs(2), second)
+            .await
+            .unwrap()
+            .unwrap(),
+        consts::RESPONSE_OK.as_bytes()
+    );
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_broker_errors_do_not_ban() {
+    let mut broker = mockito::Server::new_async().await;
+    let failing = broker
+        .mock("GET", mockito::Matcher::Any)
+        .with_status(500)
+        .create_async()
+        .await;
+    let mut config = fake::config::read().await;
+    config.uds_server = broker.url();
+    config.ban_failures = 1;
+    let server = fake::tunnel_server::TunnelServer::create(&config, false).await;
+
+    // Not the client fault, so not banned
+    for _ in 0..2 {
+        assert_eq!(
+            command(config.listen_port, &open_command("")).await,
+            consts::RESPONSE_ERROR_TICKET.as_bytes()
+        );
+    }
+    assert_eq!(server.stats.get_failure("ticket", "error"), 2);
+    assert_eq!(server.stats.get_failure("limit", "ban"), 0);
+    failing.remove_async().await;
+
+    // But a ticket rejected by the broker counts
+    broker
+        .mock("GET", mockito::Matcher::Any)
+        .with_status(404)
+        .create_async()
+        .await;
+    assert_eq!(
+        command(config.listen_port, &open_command("")).await,
+        consts::RESPONSE_ERROR_TICKET.as_bytes()
+    );
+    assert_eq!(server.stats.get_failure("ticket", "invalid"), 1);
+    assert_eq!(server.stats.get_failure("limit", "ban"), 1);
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
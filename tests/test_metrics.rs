@@ -0,0 +1,86 @@
+#[cfg(test)]
+extern crate udstunnel;
+
+mod fake;
+
+use std::sync::Arc;
+
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    task::JoinHandle,
+};
+
+use udstunnel::tunnel::{config, event, metrics, stats};
+
+async fn start_metrics(
+    config: &config::Config,
+    stats: Arc<stats::Stats>,
+) -> (event::Event, JoinHandle<()>) {
+    let stopper = event::Event::new();
+    let task_stopper = stopper.clone();
+    let mut server = metrics::MetricsServer::new(config, stats);
+    // Bound before running, as done before dropping privileges
+    server.listen().await.unwrap();
+    let handle = tokio::spawn(async move {
+        server.run(task_stopper).await.unwrap();
+    });
+    (stopper, handle)
+}
+
+async fn get(port: u16, path: &str) -> String {
+    let mut stream = TcpStream::connect(format!("127.0.0.1:{}", port))
+        .await
+        .unwrap();
+    stream
+        .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint() {
+    let mut config = fake::config::read().await;
+    config.metrics_address = "127.0.0.1".to_string();
+    config.metrics_port = fake::utils::find_free_port(Some("127.0.0.1"));
+
+    let stats = Arc::new(stats::Stats::new());
+    stats.add_global_connection();
+    stats.add_recv_bytes(2009);
+    stats.add_failure("ticket", "error");
+
+    let (stopper, handle) = start_metrics(&config, stats.clone()).await;
+
+    let response = get(config.metrics_port, "/metrics").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("udstunnel_connections_total 1\n"));
+    assert!(response.contains("udstunnel_received_bytes_total 2009\n"));
+    assert!(response.contains("udstunnel_failures_total{stage=\"ticket\",reason=\"error\"} 1\n"));
+
+    let response = get(config.metrics_port, "/other").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    stopper.set().unwrap();
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_metrics_forbidden() {
+    let mut config = fake::config::read().await;
+    config.metrics_address = "127.0.0.1".to_string();
+    config.metrics_port = fake::utils::find_free_port(Some("127.0.0.1"));
+    config.allow = config::NetworkList::parse("allow", "127.0.0.2").unwrap();
+
+    let (stopper, handle) = start_metrics(&config, Arc::new(stats::Stats::new())).await;
+
+    let response = get(config.metrics_port, "/metrics").await;
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    assert!(!response.contains("udstunnel_"));
+
+    stopper.set().unwrap();
+    handle.await.unwrap();
+}
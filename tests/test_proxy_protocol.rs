@@ -0,0 +1,115 @@
+extern crate udstunnel;
+
+mod fake;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use udstunnel::tunnel::{config, consts, proxy_protocol::ProxyProtocol};
+
+const PROXY_V1_HEADER: &[u8] = b"PROXY TCP4 203.0.113.7 198.51.100.1 40000 443\r\n";
+
+fn open_command() -> String {
+    format!("{}{}", consts::COMMAND_OPEN, "x".repeat(consts::TICKET_LENGTH))
+}
+
+#[tokio::test]
+async fn test_proxy_header_from_trusted_proxy() {
+    let config = fake::config::read().await;
+    assert_eq!(config.proxy_protocol, ProxyProtocol::Accept);
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+    let mut buffer = [0; 128];
+
+    let mut client =
+        fake::client::open_client_with_proxy_header(config.listen_port, PROXY_V1_HEADER)
+            .await
+            .unwrap();
+    client.write_all(open_command().as_bytes()).await.unwrap();
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_OK.as_bytes());
+    client.shutdown().await.unwrap();
+
+    // The broker receives the address of the real client, not the proxy one
+    assert_eq!(
+        server.requests.as_ref().unwrap().lock().unwrap()[0].message,
+        "203.0.113.7"
+    );
+
+    // On accept mode, direct connections keep working
+    let mut client = fake::client::open_client_with_handshake(config.listen_port).await;
+    client.write_all(consts::COMMAND_TEST.as_bytes()).await.unwrap();
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_OK.as_bytes());
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_proxy_header_required() {
+    let mut config = fake::config::read().await;
+    config.proxy_protocol = ProxyProtocol::Require;
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    let mut client = fake::client::open_client_no_handshake(config.listen_port).await;
+    client.write_all(consts::HANDSHAKE_V1).await.unwrap();
+    let mut buffer = [0; 128];
+    assert_eq!(client.read(&mut buffer).await.unwrap_or_default(), 0);
+    assert_eq!(server.stats.get_failure("proxy", "missing"), 1);
+
+    let mut client = fake::client::open_client_no_handshake(config.listen_port).await;
+    client
+        .write_all(b"PROXY TCP4 203.0.113.7 198.51.100.1 40000\r\n")
+        .await
+        .unwrap();
+    assert_eq!(client.read(&mut buffer).await.unwrap_or_default(), 0);
+    assert_eq!(server.stats.get_failure("proxy", "invalid"), 1);
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_proxy_header_from_untrusted_peer() {
+    let mut config = fake::config::read().await;
+    config.proxy_trusted = config::NetworkList::parse("proxy_trusted", "192.0.2.250").unwrap();
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    // Not parsed, so the handshake is invalid
+    assert!(
+        fake::client::open_client_with_proxy_header(config.listen_port, PROXY_V1_HEADER)
+            .await
+            .is_err()
+    );
+    assert_eq!(server.stats.get_failure("handshake", "invalid"), 1);
+    server.abort();
+    server.server_handle.await.unwrap();
+
+    config.proxy_protocol = ProxyProtocol::Require;
+    config.listen_port = fake::utils::find_free_port(Some(&config.listen_address));
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+    assert!(
+        fake::client::open_client_with_proxy_header(config.listen_port, PROXY_V1_HEADER)
+            .await
+            .is_err()
+    );
+    assert_eq!(server.stats.get_failure("proxy", "untrusted"), 1);
+    server.abort();
+    server.server_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_proxy_header_with_empty_trusted_list() {
+    let mut config = fake::config::read().await;
+    // Not loadable from a file, but an empty list must trust nobody anyway
+    config.proxy_trusted = config::NetworkList::default();
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    assert!(
+        fake::client::open_client_with_proxy_header(config.listen_port, PROXY_V1_HEADER)
+            .await
+            .is_err()
+    );
+    assert_eq!(server.stats.get_failure("handshake", "invalid"), 1);
+    server.abort();
+    server.server_handle.await.unwrap();
+}
@@ -0,0 +1,139 @@
+#[cfg(test)]
+extern crate udstunnel;
+
+mod fake;
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+use tokio_rustls::client::TlsStream;
+
+use udstunnel::tunnel::consts;
+
+async fn open_relay(port: u16, ticket_prefix: &str) -> TlsStream<TcpStream> {
+    let mut client = fake::client::open_client_with_handshake(port).await;
+    let command = format!(
+        "{}{}{}",
+        consts::COMMAND_OPEN,
+        ticket_prefix,
+        "x".repeat(consts::TICKET_LENGTH - ticket_prefix.len())
+    );
+    client.write_all(command.as_bytes()).await.unwrap();
+    let mut buffer = [0; 128];
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_OK.as_bytes());
+    client
+}
+
+// Waits for the relay to be closed by the tunnel
+async fn wait_closed(client: &mut TlsStream<TcpStream>, max: Duration) {
+    let mut buffer = [0; 128];
+    let closed = timeout(max, client.read(&mut buffer)).await.unwrap();
+    assert!(matches!(closed, Ok(0) | Err(_)));
+}
+
+// Query params of the end notification, once received by the broker
+async fn stop_params(reqs: &Arc<Mutex<Vec<fake::tunnel_server::Request>>>) -> String {
+    for _ in 0..20 {
+        if let Some(stop) = reqs.lock().unwrap().iter().find(|r| r.message == "stop") {
+            return stop.query_params.clone().unwrap_or_default();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("No stop notification received");
+}
+
+#[tokio::test]
+async fn test_idle_timeout() {
+    let mut config = fake::config::read().await;
+    config.idle_timeout = Duration::from_millis(500);
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+    let reqs = server.requests.clone().unwrap();
+
+    let mut client = open_relay(config.listen_port, "").await;
+    // Activity resets the idle timer
+    for _ in 0..3 {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        client.write_all(b"ping").await.unwrap();
+        let mut buffer = [0; 128];
+        let n = client.read(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"ping");
+    }
+    wait_closed(&mut client, Duration::from_secs(2)).await;
+
+    assert!(stop_params(&reqs).await.ends_with("&reason=idle_timeout"));
+    assert!(server.sessions.is_empty());
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_max_session_duration() {
+    let mut config = fake::config::read().await;
+    config.max_session_duration = Duration::from_secs(1);
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+    let reqs = server.requests.clone().unwrap();
+
+    let mut client = open_relay(config.listen_port, "").await;
+    let start = std::time::Instant::now();
+    // Closed even if not idle
+    loop {
+        if client.write_all(b"ping").await.is_err() {
+            break;
+        }
+        let mut buffer = [0; 128];
+        match timeout(Duration::from_secs(2), client.read(&mut buffer)).await {
+            Ok(Ok(0)) | Ok(Err(_)) => break,
+            Ok(Ok(_)) => tokio::time::sleep(Duration::from_millis(100)).await,
+            Err(_) => panic!("Relay not closed"),
+        }
+    }
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    assert!(start.elapsed() < Duration::from_secs(3));
+
+    assert!(stop_params(&reqs)
+        .await
+        .ends_with("&reason=max_session_duration"));
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_idle_timeout_from_ticket() {
+    // Configured timeout is one hour, but the ticket says 1 second
+    let config = fake::config::read().await;
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+    let reqs = server.requests.clone().unwrap();
+
+    let mut client = open_relay(config.listen_port, "idle").await;
+    wait_closed(&mut client, Duration::from_secs(3)).await;
+
+    assert!(stop_params(&reqs).await.ends_with("&reason=idle_timeout"));
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_close_reason_client() {
+    let config = fake::config::read().await;
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+    let reqs = server.requests.clone().unwrap();
+
+    let mut client = open_relay(config.listen_port, "").await;
+    client.shutdown().await.unwrap();
+
+    assert!(stop_params(&reqs).await.ends_with("&reason=client_closed"));
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}
@@ -16,7 +16,7 @@ use tokio::{
 
 //#[cfg_attr(test, automock)]
 
-use udstunnel::tunnel::consts;
+use udstunnel::tunnel::{client, config, consts};
 
 #[tokio::test]
 async fn test_server_test_command() {
@@ -136,6 +136,57 @@ async fn test_server_stats() {
     assert_eq!(stats[3], server.stats.get_recv_bytes().to_string());
 }
 
+#[tokio::test]
+async fn test_server_stats_forbidden() {
+    let mut config = fake::config::read().await;
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+    let wrong_secret = "0".repeat(consts::SECRET_LENGTH);
+    let mut buffer = [0; 8192];
+
+    // Wrong secret, only FORBIDDEN is sent, no stats
+    for command in [consts::COMMAND_STATS, consts::COMMAND_INFO] {
+        let mut client = fake::client::open_client_with_handshake(config.listen_port).await;
+        client
+            .write_all(format!("{}{}", command, wrong_secret).as_bytes())
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap_or_default();
+        assert_eq!(response, consts::RESPONSE_FORBIDDEN.as_bytes());
+    }
+    assert_eq!(server.stats.get_failure("command", "forbidden"), 2);
+    server.abort();
+    server.server_handle.await.unwrap();
+
+    // Without allow list, the secret is still required
+    config.allow = config::NetworkList::default();
+    config.listen_port = fake::utils::find_free_port(Some(&config.listen_address));
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+    let mut client = fake::client::open_client_with_handshake(config.listen_port).await;
+    client
+        .write_all(format!("{}{}", consts::COMMAND_STATS, wrong_secret).as_bytes())
+        .await
+        .unwrap();
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_FORBIDDEN.as_bytes());
+
+    let mut client = fake::client::open_client_with_handshake(config.listen_port).await;
+    client
+        .write_all(format!("{}{}", consts::COMMAND_STATS, config.secret).as_bytes())
+        .await
+        .unwrap();
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(
+        std::str::from_utf8(&buffer[..n])
+            .unwrap()
+            .split(';')
+            .count(),
+        4
+    );
+    server.abort();
+    server.server_handle.await.unwrap();
+}
+
 #[tokio::test]
 async fn test_invalid_command() {
     let config = fake::config::read().await;
@@ -210,3 +261,80 @@ async fn test_timedout_command() {
         }
     }
 }
+
+#[tokio::test]
+async fn test_client_request_stats() {
+    let config = fake::config::read().await;
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    server.stats.add_recv_bytes(1972);
+    server.stats.add_send_bytes(2009);
+    server.stats.add_global_connection();
+
+    let report = client::request_stats("[::1]", config.listen_port, &config.secret, false)
+        .await
+        .unwrap();
+    assert_eq!(report.global, server.stats.info());
+    assert!(report.sessions.is_empty());
+
+    // Invalid secret is not even sent
+    assert!(
+        client::request_stats("[::1]", config.listen_port, "short", false)
+            .await
+            .is_err()
+    );
+
+    server.abort();
+
+    match server.server_handle.await {
+        Ok(_) => (),
+        Err(e) => {
+            panic!("Error: {:?}", e);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_server_info() {
+    let config = fake::config::read().await;
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    // Open a relay, so it appears on the detailed info
+    let mut client = fake::client::open_client_with_handshake(config.listen_port).await;
+    let ticket = [b'x'; consts::TICKET_LENGTH];
+    let command = format!(
+        "{}{}",
+        consts::COMMAND_OPEN,
+        std::str::from_utf8(&ticket).unwrap()
+    );
+    client.write_all(command.as_bytes()).await.unwrap();
+    let mut buffer = [0; 128];
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_OK.as_bytes());
+
+    let report = client::request_stats("[::1]", config.listen_port, &config.secret, true)
+        .await
+        .unwrap();
+    assert_eq!(report.global.concurrent_connections, 1);
+    assert_eq!(report.sessions.len(), 1);
+    assert_eq!(report.sessions[0].ticket, "xxxxxxxx");
+    assert!(report.sessions[0].dst.starts_with("[::1]:"));
+
+    // Once closed, the relay is no longer listed
+    client.shutdown().await.unwrap();
+    drop(client);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let report = client::request_stats("[::1]", config.listen_port, &config.secret, true)
+        .await
+        .unwrap();
+    assert!(report.sessions.is_empty());
+
+    server.abort();
+
+    match timeout(Duration::from_secs(4), server.server_handle).await {
+        Ok(_) => (),
+        Err(e) => {
+            panic!("Error: {:?}", e);
+        }
+    }
+}
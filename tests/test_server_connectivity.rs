@@ -92,3 +92,75 @@ async fn test_server_handshake_timeout() {
         }
     }
 }
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_server_runtime_per_core_shares_port() {
+    let mut config = fake::config::read().await;
+    config.runtime_per_core = true;
+    // Both listeners bind the same port thanks to SO_REUSEPORT
+    let first = fake::tunnel_server::TunnelServer::create(&config, true).await;
+    let second = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    for _ in 0..4 {
+        let mut client = fake::client::open_client_with_handshake(config.listen_port).await;
+        client.shutdown().await.unwrap();
+    }
+
+    first.abort();
+    second.abort();
+
+    for server in [first, second] {
+        match server.server_handle.await {
+            Ok(_) => (),
+            Err(e) => {
+                panic!("Error: {:?}", e);
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_server_certificate_reload() {
+    let mut config = fake::config::read().await;
+    // Work on a copy of the certificate, to replace it while running
+    let dir = std::env::temp_dir().join(format!("udstunnel-certs-{}", config.listen_port));
+    std::fs::create_dir_all(&dir).unwrap();
+    let cert = dir.join("cert.pem");
+    let key = dir.join("key.pem");
+    std::fs::copy("tests/certs/cert.pem", &cert).unwrap();
+    std::fs::copy("tests/certs/key.pem", &key).unwrap();
+    config.ssl_certificate = cert.to_string_lossy().to_string();
+    config.ssl_certificate_key = key.to_string_lossy().to_string();
+
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    let peer_certificate = |client: &tokio_rustls::client::TlsStream<tokio::net::TcpStream>| {
+        client.get_ref().1.peer_certificates().unwrap()[0].clone()
+    };
+
+    // Connection established before the reload keeps working
+    let mut established = fake::client::open_client_with_handshake(config.listen_port).await;
+    let original = peer_certificate(&established);
+
+    std::fs::copy("tests/certs/cert2.pem", &cert).unwrap();
+    std::fs::copy("tests/certs/key2.pem", &key).unwrap();
+    server.cert_resolver.reload().unwrap();
+
+    let mut client = fake::client::open_client_with_handshake(config.listen_port).await;
+    assert_ne!(peer_certificate(&client), original);
+    client.shutdown().await.unwrap();
+
+    // The old connection is still alive, so the server answers the (invalid) command
+    established.write_all(b"XXXX").await.unwrap();
+    let mut buf = vec![0u8; 32];
+    let size = timeout(Duration::from_secs(2), established.read(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[..size], consts::RESPONSE_ERROR_COMMAND.as_bytes());
+
+    server.abort();
+    server.server_handle.await.unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}
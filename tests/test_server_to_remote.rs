@@ -8,7 +8,7 @@ use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
 };
 
-use udstunnel::tunnel::consts;
+use udstunnel::tunnel::{audit, consts};
 
 //#[cfg(test)]
 //use mockall::automock;
@@ -59,3 +59,93 @@ async fn test_server_to_remote() {
         }
     }
 }
+
+#[tokio::test]
+async fn test_server_to_remote_audit() {
+    let mut config = fake::config::read().await;
+    let audit_file = std::env::temp_dir().join(format!("udstunnel-audit-{}.log", config.listen_port));
+    config.audit_file = Some(audit_file.to_string_lossy().to_string());
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+
+    let mut client = fake::client::open_client_with_handshake(config.listen_port).await;
+    let ticket = [b'x'; consts::TICKET_LENGTH];
+    let command = format!(
+        "{}{}",
+        consts::COMMAND_OPEN,
+        std::str::from_utf8(&ticket).unwrap()
+    );
+    client.write_all(command.as_bytes()).await.unwrap();
+    let mut buffer = [0; 128];
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_OK.as_bytes());
+
+    let data = [b'x'; 128];
+    client.write_all(&data).await.unwrap();
+    let mut buffer = [0; 1024];
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(n, data.len());
+
+    client.shutdown().await.unwrap();
+    drop(client);
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+
+    let content = std::fs::read_to_string(&audit_file).unwrap();
+    let records = content
+        .lines()
+        .map(|l| serde_json::from_str::<audit::AuditRecord>(l).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].event, "terminated");
+    assert_eq!(records[0].ticket, "xxxxxxxx");
+    assert_eq!(records[0].sent_bytes, 128);
+    assert_eq!(records[0].recv_bytes, 128);
+    assert!(records[0].dst.starts_with("[::1]:"));
+
+    std::fs::remove_file(audit_file).unwrap();
+    server.abort();
+}
+
+#[tokio::test]
+async fn test_server_to_remote_virtual_host() {
+    let config = fake::config::read().await;
+    let server = fake::tunnel_server::TunnelServer::create(&config, true).await;
+    let ticket = [b'x'; consts::TICKET_LENGTH];
+    let command = format!(
+        "{}{}",
+        consts::COMMAND_OPEN,
+        std::str::from_utf8(&ticket).unwrap()
+    );
+
+    // SNI of the virtual host, gets its certificate and its broker
+    let mut client =
+        fake::client::open_client_with_server_name(config.listen_port, "tunnel.customer1.test")
+            .await;
+    let virtual_host_cert = client.get_ref().1.peer_certificates().unwrap()[0].clone();
+    client.write_all(command.as_bytes()).await.unwrap();
+    let mut buffer = [0; 128];
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_OK.as_bytes());
+    client.shutdown().await.unwrap();
+
+    // Unknown SNI, global certificate and broker
+    let mut client =
+        fake::client::open_client_with_server_name(config.listen_port, "other.example.com").await;
+    assert_ne!(
+        client.get_ref().1.peer_certificates().unwrap()[0],
+        virtual_host_cert
+    );
+    client.write_all(command.as_bytes()).await.unwrap();
+    let n = client.read(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], consts::RESPONSE_OK.as_bytes());
+    client.shutdown().await.unwrap();
+
+    // Open (and maybe already the stop notification) went to each broker
+    assert_eq!(
+        server.virtual_host_requests["customer1"].lock().unwrap()[0].ticket,
+        std::str::from_utf8(&ticket).unwrap()
+    );
+    assert!(!server.requests.as_ref().unwrap().lock().unwrap().is_empty());
+
+    server.abort();
+    server.server_handle.await.unwrap();
+}